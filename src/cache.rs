@@ -0,0 +1,125 @@
+//! On-disk cache of fetched source lists, used for conditional requests.
+
+use crate::output::write_atomic;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Cache of the last body fetched from each source URL, with the ETag and
+/// Last-Modified validators the server sent for it.
+///
+/// Each URL maps to two files named after a hash of the URL: `<key>.body`
+/// holds the decompressed response body (before character decoding, so a
+/// cached copy goes through exactly the same parsing as a fresh one) and
+/// `<key>.toml` the metadata. Both are written atomically, body first, so a
+/// metadata file never describes a body that isn't fully on disk.
+#[derive(Debug, Clone)]
+pub struct FetchCache {
+    dir: PathBuf,
+}
+
+/// Metadata stored next to each cached body.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CacheMeta {
+    /// Full URL, checked on load to rule out hash collisions.
+    pub(crate) url: String,
+    pub(crate) etag: Option<String>,
+    pub(crate) last_modified: Option<String>,
+    /// Unix timestamp of the last time the server confirmed this body,
+    /// either with a 200 or a 304 response.
+    pub(crate) fetched_at: i64,
+}
+
+/// A cached response body and its metadata.
+pub(crate) struct CachedBody {
+    pub(crate) meta: CacheMeta,
+    pub(crate) body: Vec<u8>,
+}
+
+impl FetchCache {
+    /// Uses `dir` for cache files. It is created on first store.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FetchCache { dir: dir.into() }
+    }
+
+    fn paths(&self, url: &str) -> (PathBuf, PathBuf) {
+        let key = format!("{:016x}", fnv1a64(url.as_bytes()));
+        (
+            self.dir.join(format!("{key}.toml")),
+            self.dir.join(format!("{key}.body")),
+        )
+    }
+
+    /// Loads the cached body for `url`, or None if there is none or it
+    /// can't be read.
+    pub(crate) async fn load(&self, url: &str) -> Option<CachedBody> {
+        let (meta_path, body_path) = self.paths(url);
+        let meta = tokio::fs::read_to_string(&meta_path).await.ok()?;
+        let meta: CacheMeta = toml::from_str(&meta).ok()?;
+        if meta.url != url {
+            return None;
+        }
+        let body = tokio::fs::read(&body_path).await.ok()?;
+        Some(CachedBody { meta, body })
+    }
+
+    /// Stores a freshly downloaded body with its validators.
+    pub(crate) async fn store(&self, meta: &CacheMeta, body: &[u8]) -> std::io::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let (_, body_path) = self.paths(&meta.url);
+        write_atomic(&body_path, body).await?;
+        self.store_meta(meta).await
+    }
+
+    /// Rewrites only the metadata, e.g. to record a 304 revalidation.
+    pub(crate) async fn store_meta(&self, meta: &CacheMeta) -> std::io::Result<()> {
+        let (meta_path, _) = self.paths(&meta.url);
+        let content = toml::to_string(meta).map_err(std::io::Error::other)?;
+        write_atomic(&meta_path, content.as_bytes()).await
+    }
+}
+
+/// 64-bit FNV-1a hash. Used for stable file names and ETags: unlike std's
+/// DefaultHasher, its output is guaranteed not to change between Rust
+/// releases, so cache files survive a toolchain upgrade.
+pub(crate) fn fnv1a64(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in bytes {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_fnv1a64_known_values() {
+        assert_eq!(fnv1a64(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a64(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[tokio::test]
+    async fn test_store_and_load_round_trip() {
+        let dir = tempdir().unwrap();
+        let cache = FetchCache::new(dir.path().join("cache"));
+        let url = "https://example.com/list.txt";
+        assert!(cache.load(url).await.is_none());
+
+        let meta = CacheMeta {
+            url: url.to_string(),
+            etag: Some("\"abc\"".to_string()),
+            last_modified: None,
+            fetched_at: 1_700_000_000,
+        };
+        cache.store(&meta, b"||example.com^\n").await.unwrap();
+
+        let cached = cache.load(url).await.expect("cached body");
+        assert_eq!(cached.body, b"||example.com^\n");
+        assert_eq!(cached.meta.etag.as_deref(), Some("\"abc\""));
+        assert_eq!(cached.meta.fetched_at, 1_700_000_000);
+        assert!(cache.load("https://example.com/other.txt").await.is_none());
+    }
+}
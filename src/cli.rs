@@ -0,0 +1,265 @@
+//! Command-line argument parsing.
+//!
+//! Hand-rolled rather than pulling in an argument parser crate: the surface
+//! is a handful of subcommands and flags, and keeping dependencies minimal
+//! keeps the Docker image and build times small.
+
+use adblock2mikrotik_rust::{ReportFormat, Verbosity};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: adblock2mikrotik_rust [COMMAND] [OPTIONS]
+
+Commands:
+  build             Fetch all sources and write the output files (default)
+  check             Validate the config, fetch and convert everything,
+                    run the safety checks, write nothing
+  lookup <DOMAIN>   Show every rule that blocks DOMAIN or a parent domain,
+                    and whether the allowlist or an exception overrides it
+  daemon            Keep running and rebuild on the [daemon] schedule
+                    until SIGTERM or Ctrl-C
+  serve             Like daemon, and also serve the generated files over
+                    HTTP
+
+Options:
+  -c, --config <FILE>   Config file to read [default: config.toml]
+  -o, --output <DIR>    Directory to write to [default: $OUTPUT_DIR or the
+                        current directory]
+  -s, --source <URL>    Source list to convert, instead of the configured
+                        sources (repeatable): a URL, a file path, or -
+                        for stdin
+      --force           Write the output even if it fails the
+                        max_change_percent check
+      --report <FORMAT[=PATH]>
+                        Also write a build report (json) to PATH, or to
+                        build-report.json in the output directory; check
+                        only writes one to PATH
+      --listen <ADDR>   Address for serve to listen on [default: the
+                        [serve] listen setting or 0.0.0.0:8080]
+  -q, --quiet           Only print warnings and errors
+      --verbose         Print per-source details
+  -h, --help            Print this help
+  -V, --version         Print the version
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Build,
+    Check,
+    Lookup,
+    Daemon,
+    Serve,
+    Help,
+    Version,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Cli {
+    pub command: Command,
+    /// Domain to explain, for `lookup`.
+    pub domain: Option<String>,
+    pub config: PathBuf,
+    pub output: Option<PathBuf>,
+    /// Sources given with --source; when non-empty they replace the
+    /// configured ones.
+    pub sources: Vec<String>,
+    pub force: bool,
+    pub verbosity: Verbosity,
+    pub report: Option<ReportFormat>,
+    /// File given with `--report json=PATH`.
+    pub report_path: Option<PathBuf>,
+    /// Address given with --listen, for `serve`.
+    pub listen: Option<SocketAddr>,
+}
+
+impl Default for Cli {
+    fn default() -> Self {
+        Cli {
+            command: Command::Build,
+            domain: None,
+            config: PathBuf::from("config.toml"),
+            output: None,
+            sources: Vec::new(),
+            force: false,
+            verbosity: Verbosity::Normal,
+            report: None,
+            report_path: None,
+            listen: None,
+        }
+    }
+}
+
+/// Parses the arguments following the program name. Options take their
+/// value either as the next argument or after `=` (`--config=path`).
+pub fn parse<I>(args: I) -> Result<Cli, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut cli = Cli::default();
+    let mut command: Option<Command> = None;
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{name} requires a value"))
+        };
+        match name {
+            "-c" | "--config" => cli.config = PathBuf::from(value()?),
+            "-o" | "--output" => cli.output = Some(PathBuf::from(value()?)),
+            "-s" | "--source" => cli.sources.push(value()?),
+            "--force" => cli.force = true,
+            "--report" => {
+                let value = value()?;
+                let (format, path) = match value.split_once('=') {
+                    Some((format, path)) => (format, Some(PathBuf::from(path))),
+                    None => (value.as_str(), None),
+                };
+                cli.report = Some(format.parse().map_err(|e| format!("{e}"))?);
+                cli.report_path = path;
+            }
+            "--listen" => {
+                let addr = value()?;
+                cli.listen = Some(
+                    addr.parse()
+                        .map_err(|_| format!("invalid --listen address: {addr}"))?,
+                );
+            }
+            "-q" | "--quiet" => cli.verbosity = Verbosity::Quiet,
+            "--verbose" => cli.verbosity = Verbosity::Verbose,
+            // -v has always printed the version, so it stays that way
+            "-h" | "--help" => return Ok(Cli::with_command(Command::Help)),
+            "-v" | "-V" | "--version" => return Ok(Cli::with_command(Command::Version)),
+            _ if name.starts_with('-') => return Err(format!("unknown option: {name}")),
+            _ if command.is_none() => {
+                command = Some(match name {
+                    "build" => Command::Build,
+                    "check" => Command::Check,
+                    "lookup" => Command::Lookup,
+                    "daemon" => Command::Daemon,
+                    "serve" => Command::Serve,
+                    "help" => return Ok(Cli::with_command(Command::Help)),
+                    _ => return Err(format!("unknown command: {name}")),
+                });
+            }
+            _ if command == Some(Command::Lookup) && cli.domain.is_none() => {
+                cli.domain = Some(name.to_string());
+            }
+            _ => return Err(format!("unexpected argument: {name}")),
+        }
+    }
+
+    cli.command = command.unwrap_or(Command::Build);
+    if cli.command == Command::Lookup && cli.domain.is_none() {
+        return Err("lookup requires a domain".to_string());
+    }
+    Ok(cli)
+}
+
+impl Cli {
+    fn with_command(command: Command) -> Self {
+        Cli {
+            command,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Cli, String> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_no_arguments_is_default_build() {
+        assert_eq!(parse_args(&[]).unwrap(), Cli::default());
+    }
+
+    #[test]
+    fn test_check_with_options() {
+        let cli = parse_args(&[
+            "check",
+            "--config",
+            "/etc/a2m.toml",
+            "--output=/srv/www",
+            "--source=https://example.com/a.txt",
+            "-s",
+            "https://example.com/b.txt",
+            "--verbose",
+        ])
+        .unwrap();
+        assert_eq!(cli.command, Command::Check);
+        assert_eq!(cli.config, PathBuf::from("/etc/a2m.toml"));
+        assert_eq!(cli.output, Some(PathBuf::from("/srv/www")));
+        assert_eq!(
+            cli.sources,
+            vec!["https://example.com/a.txt", "https://example.com/b.txt"]
+        );
+        assert_eq!(cli.verbosity, Verbosity::Verbose);
+    }
+
+    #[test]
+    fn test_options_before_command_and_flags() {
+        let cli = parse_args(&["-q", "--force", "daemon"]).unwrap();
+        assert_eq!(cli.command, Command::Daemon);
+        assert_eq!(cli.verbosity, Verbosity::Quiet);
+        assert!(cli.force);
+    }
+
+    #[test]
+    fn test_version_and_help() {
+        for flag in ["-v", "-V", "--version"] {
+            assert_eq!(parse_args(&[flag]).unwrap().command, Command::Version);
+        }
+        assert_eq!(parse_args(&["--help"]).unwrap().command, Command::Help);
+        assert_eq!(parse_args(&["check", "-h"]).unwrap().command, Command::Help);
+    }
+
+    #[test]
+    fn test_lookup_takes_a_domain() {
+        let cli = parse_args(&["lookup", "ads.example.com", "-c", "my.toml"]).unwrap();
+        assert_eq!(cli.command, Command::Lookup);
+        assert_eq!(cli.domain.as_deref(), Some("ads.example.com"));
+        assert_eq!(cli.config, PathBuf::from("my.toml"));
+        assert!(parse_args(&["lookup"]).is_err());
+        assert!(parse_args(&["lookup", "a.com", "b.com"]).is_err());
+    }
+
+    #[test]
+    fn test_report_format() {
+        let cli = parse_args(&["check", "--report", "json"]).unwrap();
+        assert_eq!(cli.report, Some(ReportFormat::Json));
+        assert_eq!(cli.report_path, None);
+        let cli = parse_args(&["--report=json=out/report.json"]).unwrap();
+        assert_eq!(cli.report, Some(ReportFormat::Json));
+        assert_eq!(cli.report_path, Some(PathBuf::from("out/report.json")));
+        assert!(parse_args(&["--report=xml"]).is_err());
+        assert!(parse_args(&["--report", "xml=report.xml"]).is_err());
+    }
+
+    #[test]
+    fn test_serve_listen() {
+        let cli = parse_args(&["serve", "--listen", "127.0.0.1:8081"]).unwrap();
+        assert_eq!(cli.command, Command::Serve);
+        assert_eq!(cli.listen, Some("127.0.0.1:8081".parse().unwrap()));
+        assert!(parse_args(&["serve", "--listen=localhost"]).is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(parse_args(&["--config"]).is_err());
+        assert!(parse_args(&["--nope"]).is_err());
+        assert!(parse_args(&["deploy"]).is_err());
+        assert!(parse_args(&["build", "check"]).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+//! Long-running mode: rebuild the output on a schedule.
+
+use crate::{
+    Converter, DirectorySink, OutputSink, RunOptions, Source, format_age, output_dir, print_logger,
+};
+use std::hash::{BuildHasher, RandomState};
+use std::time::Duration;
+
+/// When [`run_daemon`] rebuilds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule {
+    /// Time between the start of one build and the start of the next.
+    pub interval: Duration,
+    /// Up to this much is added to each interval at random, so several
+    /// instances don't hit the upstream servers at the same moment.
+    pub jitter: Duration,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule {
+            interval: Duration::from_secs(6 * 3_600),
+            jitter: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+impl Schedule {
+    /// The interval plus a random share of the jitter.
+    fn next_delay(&self) -> Duration {
+        let jitter_secs = self.jitter.as_secs();
+        if jitter_secs == 0 {
+            return self.interval;
+        }
+        // RandomState is seeded randomly per instance, which is all the
+        // randomness a jitter needs; no need for a rand dependency
+        let random = RandomState::new().hash_one(std::time::SystemTime::now());
+        self.interval + Duration::from_secs(random % (jitter_secs + 1))
+    }
+}
+
+/// Builds right away, then again on every tick of `schedule`, until
+/// `shutdown` completes.
+///
+/// Every build reuses the converter's HTTP client (and so its open
+/// connections) and, with [`crate::RunOptions::cache_dir`] set, revalidates
+/// the cached lists instead of downloading them again. A failed build is
+/// logged and retried at the next tick.
+///
+/// On shutdown during a build, downloads and the deployment to
+/// [`crate::RunOptions::routers`] in progress are abandoned, but output
+/// files being written are always finished first, so no temp file or
+/// half-written list is left behind.
+///
+/// Fails before the first build if a source is `-`: stdin is used up by the
+/// first build, so every rebuild after it would silently lose that source
+/// (and could trip [`crate::RunOptions::max_change_percent`]).
+pub async fn run_daemon<S, F>(
+    converter: &Converter,
+    sink: &mut S,
+    schedule: &Schedule,
+    shutdown: F,
+) -> std::io::Result<()>
+where
+    S: OutputSink,
+    F: Future<Output = ()>,
+{
+    reject_stdin(converter)?;
+    let console = &converter.console;
+    tokio::pin!(shutdown);
+    loop {
+        let started = tokio::time::Instant::now();
+        let conversion = tokio::select! {
+            conversion = converter.convert() => conversion,
+            () = &mut shutdown => break,
+        };
+        // Only the deployment is raced against shutdown: stopping mid-write
+        // is what would leave temp files behind
+        let mut stopping = false;
+        let finished = converter.finish_until(conversion, sink, async {
+            (&mut shutdown).await;
+            stopping = true;
+        });
+        if let Err(e) = finished.await {
+            console.warn(format_args!("build failed: {e}"));
+        }
+        if stopping {
+            break;
+        }
+
+        let delay = schedule.next_delay();
+        console.info(format_args!(
+            "Next build in {}\n",
+            format_age(delay.saturating_sub(started.elapsed()).as_secs())
+        ));
+        tokio::select! {
+            () = tokio::time::sleep_until(started + delay) => {}
+            () = &mut shutdown => break,
+        }
+    }
+    console.info("Shutting down");
+    Ok(())
+}
+
+/// The error of [`run_daemon`] for a `-` (stdin) source, which only has
+/// content for one build.
+pub(crate) fn reject_stdin(converter: &Converter) -> std::io::Result<()> {
+    if converter.sources.iter().any(|source| source.url == "-") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "stdin (-) can't be a source when rebuilding on a schedule: it is empty after the first build",
+        ));
+    }
+    Ok(())
+}
+
+/// [`run_daemon`] with the output directory and console output of
+/// [`crate::run_with_options`].
+pub async fn run_daemon_with_options<I, F>(
+    sources: I,
+    options: &RunOptions,
+    schedule: &Schedule,
+    shutdown: F,
+) -> std::io::Result<()>
+where
+    I: IntoIterator,
+    I::Item: Into<Source>,
+    F: Future<Output = ()>,
+{
+    let converter = Converter::new()
+        .sources(sources)
+        .options(options.clone())
+        .logger(print_logger(options.verbosity));
+    let mut sink = DirectorySink::new(output_dir(options));
+    run_daemon(&converter, &mut sink, schedule, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemorySink;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+    use tokio::sync::Notify;
+
+    #[test]
+    fn test_next_delay_within_jitter() {
+        let schedule = Schedule {
+            interval: Duration::from_secs(3_600),
+            jitter: Duration::from_secs(60),
+        };
+        for _ in 0..20 {
+            let delay = schedule.next_delay();
+            assert!(delay >= schedule.interval);
+            assert!(delay <= schedule.interval + schedule.jitter);
+        }
+        let exact = Schedule {
+            jitter: Duration::ZERO,
+            ..schedule
+        };
+        assert_eq!(exact.next_delay(), exact.interval);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_run_daemon_rebuilds_until_shutdown() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/rules")
+            .with_status(200)
+            .with_body("||example.com^\n")
+            .expect(3)
+            .create_async()
+            .await;
+        // Stop once the third build is done. Counting builds through the
+        // logger rather than sleeping keeps paused time from racing the
+        // requests.
+        let builds = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(Notify::new());
+        let (counter, notify) = (builds.clone(), done.clone());
+        let converter = Converter::new()
+            .source(format!("{}/rules", server.url()))
+            .logger(move |_, message| {
+                if message.starts_with("Next build in") && counter.fetch_add(1, SeqCst) == 2 {
+                    notify.notify_one();
+                }
+            });
+        let schedule = Schedule {
+            interval: Duration::from_secs(3_600),
+            jitter: Duration::ZERO,
+        };
+        let mut sink = MemorySink::default();
+        let started = tokio::time::Instant::now();
+        run_daemon(&converter, &mut sink, &schedule, done.notified())
+            .await
+            .unwrap();
+
+        assert_eq!(builds.load(SeqCst), 3);
+        // Builds at 0h, 1h and 2h; shutdown interrupts the wait for the 4th
+        assert!(started.elapsed() < Duration::from_secs(3 * 3_600));
+        mock.assert_async().await;
+        assert!(sink.files.contains_key("hosts.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_run_daemon_abandons_deployment_on_shutdown() {
+        let mut server = mockito::Server::new_async().await;
+        let _rules = server
+            .mock("GET", "/rules")
+            .with_status(200)
+            .with_body("||example.com^\n")
+            .create_async()
+            .await;
+        // A router that accepts the connection but never answers, like one
+        // whose REST service hangs
+        let router = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let converter = Converter::new()
+            .source(format!("{}/rules", server.url()))
+            .options(RunOptions {
+                routers: vec![crate::Router {
+                    url: format!("http://{}", router.local_addr().unwrap()),
+                    user: "admin".to_string(),
+                    password: String::new(),
+                    action: crate::DeployAction::Reload,
+                    files_url: None,
+                    accept_invalid_certs: false,
+                }],
+                report: Some(crate::ReportFormat::Json),
+                ..Default::default()
+            })
+            .logger(|_, _| {});
+        let mut sink = MemorySink::default();
+        // Shut down as soon as the deployment has reached the router,
+        // keeping the connection open
+        let mut connection = None;
+        let shutdown = async {
+            connection = Some(router.accept().await.unwrap());
+        };
+        let ran = tokio::time::timeout(
+            Duration::from_secs(10),
+            run_daemon(&converter, &mut sink, &Schedule::default(), shutdown),
+        )
+        .await;
+
+        ran.expect("shutdown waited for the deployment").unwrap();
+        assert!(sink.files.contains_key("hosts.txt"));
+        let report = String::from_utf8(sink.files["build-report.json"].clone()).unwrap();
+        assert!(report.contains("abandoned on shutdown"), "{report}");
+    }
+
+    #[tokio::test]
+    async fn test_run_daemon_rejects_stdin() {
+        let converter = Converter::new().sources(["https://example.com/list.txt", "-"]);
+        let mut sink = MemorySink::default();
+        let error = run_daemon(&converter, &mut sink, &Schedule::default(), async {})
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(sink.files.is_empty());
+    }
+}
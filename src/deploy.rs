@@ -0,0 +1,250 @@
+//! Pushing a fresh build to MikroTik routers through the RouterOS v7 REST
+//! API.
+
+use crate::{Console, output::split_part_file_name};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, time::Duration};
+
+/// What to do on a router after each build.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeployAction {
+    /// Run `/ip/dns/adlist reload`, so adlists added by URL are downloaded
+    /// again right away instead of at their next scheduled refresh.
+    #[default]
+    Reload,
+    /// Have the router download the generated list file(s) from
+    /// [`Router::files_url`] into its file store (creating or replacing
+    /// them), remove the parts a previous, larger build left there, then
+    /// reload. For adlists added with `file=hosts.txt`.
+    Upload,
+}
+
+/// A router reachable over the RouterOS v7 REST API (the `www-ssl` or
+/// `www` service).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Router {
+    /// Base URL of the router, e.g. `https://192.168.88.1`; requests go to
+    /// `<url>/rest/...`.
+    pub url: String,
+    pub user: String,
+    pub password: String,
+    pub action: DeployAction,
+    /// Base URL the router downloads the files from for
+    /// [`DeployAction::Upload`], e.g. the address of `serve`; file `name` is
+    /// fetched from `<files_url>/<name>`. Required for uploads.
+    pub files_url: Option<String>,
+    /// Accept a self-signed or otherwise invalid certificate, as routers
+    /// usually have one.
+    pub accept_invalid_certs: bool,
+}
+
+/// Per-router part of a [`crate::BuildReport`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DeployReport {
+    pub url: String,
+    pub action: DeployAction,
+    /// Why the deployment failed, if it did.
+    pub error: Option<String>,
+}
+
+/// Time allowed for each REST call, uploads included.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Deploys to every router in turn. `files` are the generated list files to
+/// upload. Routers with [`Router::accept_invalid_certs`] go through
+/// `insecure_client`. A failure is logged and reported but doesn't stop the
+/// other routers. Once `shutdown` completes, the deployment in progress is
+/// abandoned and the remaining routers are skipped, reported as failed.
+pub(crate) async fn deploy_all(
+    client: &reqwest::Client,
+    insecure_client: Option<&reqwest::Client>,
+    routers: &[Router],
+    files: &[String],
+    console: &Console,
+    shutdown: impl Future<Output = ()>,
+) -> Vec<DeployReport> {
+    tokio::pin!(shutdown);
+    let mut stopped = false;
+    let mut reports = Vec::with_capacity(routers.len());
+    for router in routers {
+        let client = match insecure_client {
+            Some(insecure) if router.accept_invalid_certs => insecure,
+            _ => client,
+        };
+        let result = if stopped {
+            Err(anyhow::anyhow!("abandoned on shutdown"))
+        } else {
+            tokio::select! {
+                result = deploy(client, router, files) => result,
+                () = &mut shutdown => {
+                    stopped = true;
+                    Err(anyhow::anyhow!("abandoned on shutdown"))
+                }
+            }
+        };
+        match &result {
+            Ok(()) => console.info(format_args!("Deployed to {}", router.url)),
+            Err(e) => console.warn(format_args!("failed to deploy to {}: {e}", router.url)),
+        }
+        reports.push(DeployReport {
+            url: router.url.clone(),
+            action: router.action,
+            error: result.err().map(|e| e.to_string()),
+        });
+    }
+    reports
+}
+
+async fn deploy(client: &reqwest::Client, router: &Router, files: &[String]) -> anyhow::Result<()> {
+    let rest = Rest { client, router };
+
+    if router.action == DeployAction::Upload {
+        let files_url = router
+            .files_url
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("upload needs files_url to download the files from"))?;
+        for name in files {
+            rest.fetch(&file_url(files_url, name)?, name).await?;
+        }
+        rest.remove_stale_parts(files).await?;
+    }
+    rest.call(
+        reqwest::Method::POST,
+        "ip/dns/adlist/reload",
+        Some(serde_json::json!({})),
+    )
+    .await?;
+    Ok(())
+}
+
+/// `<files_url>/<name>`, with `name` percent-encoded as a path segment.
+fn file_url(files_url: &str, name: &str) -> anyhow::Result<reqwest::Url> {
+    let mut url = reqwest::Url::parse(files_url)?;
+    url.path_segments_mut()
+        .map_err(|()| anyhow::anyhow!("files_url {files_url} can't have a file path"))?
+        .pop_if_empty()
+        .push(name);
+    Ok(url)
+}
+
+struct Rest<'a> {
+    client: &'a reqwest::Client,
+    router: &'a Router,
+}
+
+impl Rest<'_> {
+    /// Has the router download `url` into file `name` with `/tool/fetch`,
+    /// replacing the file if it exists. Sending the list itself as the
+    /// `contents` of `/rest/file` would hit RouterOS's limit on that field
+    /// long before a full blocklist fits.
+    async fn fetch(&self, url: &reqwest::Url, name: &str) -> anyhow::Result<()> {
+        let body = serde_json::json!({ "url": url.as_str(), "dst-path": name });
+        self.call(reqwest::Method::POST, "tool/fetch", Some(body))
+            .await?;
+        Ok(())
+    }
+
+    /// Removes the router's files that are parts of the uploaded lists but
+    /// weren't uploaded: `hosts-3.txt` once a build needs only two parts, or
+    /// any `hosts-N.txt` once `hosts.txt` is no longer split.
+    async fn remove_stale_parts(&self, files: &[String]) -> anyhow::Result<()> {
+        #[derive(Deserialize)]
+        struct File {
+            #[serde(rename = ".id")]
+            id: String,
+            name: String,
+        }
+        let lists: HashSet<String> = files
+            .iter()
+            .map(|name| split_part_file_name(name).map_or_else(|| name.clone(), |(list, _)| list))
+            .collect();
+        let body = self.call(reqwest::Method::GET, "file", None).await?;
+        for file in serde_json::from_slice::<Vec<File>>(&body)? {
+            if !files.contains(&file.name)
+                && split_part_file_name(&file.name).is_some_and(|(list, _)| lists.contains(&list))
+            {
+                self.call(reqwest::Method::DELETE, &format!("file/{}", file.id), None)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends one request to `<url>/rest/<path>` and returns the response
+    /// body, failing on a non-2xx status with the router's error detail.
+    async fn call(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let url = format!("{}/rest/{path}", self.router.url.trim_end_matches('/'));
+        let mut request = self
+            .client
+            .request(method.clone(), &url)
+            .basic_auth(&self.router.user, Some(&self.router.password))
+            .timeout(REQUEST_TIMEOUT);
+        if let Some(body) = body {
+            request = request
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.to_string());
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+        if !status.is_success() {
+            anyhow::bail!(
+                "{method} /rest/{path} returned {status}{}",
+                error_detail(&body)
+            );
+        }
+        Ok(body.to_vec())
+    }
+}
+
+/// `: <detail>` from a RouterOS error body such as
+/// `{"error":400,"message":"Bad Request","detail":"no such command"}`, or
+/// nothing if there is none.
+fn error_detail(body: &[u8]) -> String {
+    #[derive(Deserialize)]
+    struct RestError {
+        detail: Option<String>,
+        message: Option<String>,
+    }
+    serde_json::from_slice::<RestError>(body)
+        .ok()
+        .and_then(|e| e.detail.or(e.message))
+        .map(|detail| format!(": {detail}"))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_detail() {
+        let body = br#"{"error":400,"message":"Bad Request","detail":"no such command"}"#;
+        assert_eq!(error_detail(body), ": no such command");
+        assert_eq!(
+            error_detail(br#"{"error":401,"message":"Unauthorized"}"#),
+            ": Unauthorized"
+        );
+        assert_eq!(error_detail(b"<html>"), "");
+    }
+
+    #[test]
+    fn test_file_url() {
+        let url = |base| file_url(base, "hosts 1.txt").unwrap().to_string();
+        assert_eq!(
+            url("http://192.168.88.10:8080"),
+            "http://192.168.88.10:8080/hosts%201.txt"
+        );
+        assert_eq!(
+            url("https://example.com/lists/"),
+            "https://example.com/lists/hosts%201.txt"
+        );
+        assert!(file_url("not a url", "hosts.txt").is_err());
+    }
+}
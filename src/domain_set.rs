@@ -0,0 +1,171 @@
+use std::collections::HashSet;
+
+/// Set of domains with parent-domain lookups.
+///
+/// Equivalent to a reversed-label trie for the queries the converter needs
+/// ("is this domain, or one of its ancestors, in the set?"), but backed by a
+/// flat `HashSet<String>`: an ancestor query walks the domain's suffixes at
+/// label boundaries, costing one hash lookup per label. That keeps memory at
+/// one string per domain, where a trie would allocate a node per label —
+/// several times more for a ~300k domain list.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DomainSet {
+    domains: HashSet<String>,
+}
+
+impl DomainSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        DomainSet {
+            domains: HashSet::with_capacity(capacity),
+        }
+    }
+
+    /// Adds a domain, returning true if it was not already present.
+    /// Only allocates when the domain is new.
+    pub fn insert(&mut self, domain: &str) -> bool {
+        if self.domains.contains(domain) {
+            return false;
+        }
+        self.domains.insert(domain.to_string())
+    }
+
+    pub fn remove(&mut self, domain: &str) -> bool {
+        self.domains.remove(domain)
+    }
+
+    /// True if exactly this domain is in the set.
+    pub fn contains(&self, domain: &str) -> bool {
+        self.domains.contains(domain)
+    }
+
+    /// True if the domain itself or any of its parent domains is in the set.
+    ///
+    /// ```
+    /// use adblock2mikrotik_rust::DomainSet;
+    /// let set: DomainSet = ["example.com"].into_iter().collect();
+    /// assert!(set.covers("example.com"));
+    /// assert!(set.covers("ads.example.com"));
+    /// assert!(!set.covers("badexample.com"));
+    /// ```
+    pub fn covers(&self, domain: &str) -> bool {
+        self.covering(domain).is_some()
+    }
+
+    /// The domain itself if it is in the set, or else its closest parent
+    /// domain in the set.
+    ///
+    /// ```
+    /// use adblock2mikrotik_rust::DomainSet;
+    /// let set: DomainSet = ["example.com"].into_iter().collect();
+    /// assert_eq!(set.covering("example.com"), Some("example.com"));
+    /// assert_eq!(set.covering("ads.example.com"), Some("example.com"));
+    /// assert_eq!(set.covering("badexample.com"), None);
+    /// ```
+    pub fn covering<'a>(&self, domain: &'a str) -> Option<&'a str> {
+        if self.domains.is_empty() {
+            None
+        } else if self.contains(domain) {
+            Some(domain)
+        } else {
+            self.ancestor(domain)
+        }
+    }
+
+    /// True if a strict parent domain is in the set: "a.b.example.com"
+    /// checks "b.example.com", "example.com" and "com", but not itself.
+    pub fn has_ancestor(&self, domain: &str) -> bool {
+        self.ancestor(domain).is_some()
+    }
+
+    /// The closest strict parent domain in the set, if any.
+    ///
+    /// ```
+    /// use adblock2mikrotik_rust::DomainSet;
+    /// let set: DomainSet = ["example.com"].into_iter().collect();
+    /// assert_eq!(set.ancestor("a.b.example.com"), Some("example.com"));
+    /// assert_eq!(set.ancestor("example.com"), None);
+    /// ```
+    pub fn ancestor<'a>(&self, domain: &'a str) -> Option<&'a str> {
+        if self.domains.is_empty() {
+            return None;
+        }
+        parents(domain).find(|parent| self.domains.contains(*parent))
+    }
+
+    pub fn len(&self) -> usize {
+        self.domains.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.domains.iter().map(String::as_str)
+    }
+}
+
+/// The strict parent domains of `domain`, closest first: "a.b.example.com"
+/// yields "b.example.com", "example.com" and "com".
+pub(crate) fn parents(domain: &str) -> impl Iterator<Item = &str> {
+    domain.match_indices('.').map(|(pos, _)| &domain[pos + 1..])
+}
+
+impl<'a> FromIterator<&'a str> for DomainSet {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(iter: I) -> Self {
+        let mut set = DomainSet::new();
+        for domain in iter {
+            set.insert(domain);
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_covers_exact_and_subdomain() {
+        let set: DomainSet = ["example.com"].into_iter().collect();
+        assert!(set.covers("example.com"));
+        assert!(set.covers("ads.example.com"));
+        assert!(set.covers("a.b.example.com"));
+        // Suffix match must respect label boundaries
+        assert!(!set.covers("badexample.com"));
+        assert!(!set.covers("example.org"));
+    }
+
+    #[test]
+    fn test_has_ancestor_excludes_self() {
+        let set: DomainSet = ["example.com", "sub.other.com"].into_iter().collect();
+        assert!(!set.has_ancestor("example.com"));
+        assert!(set.has_ancestor("ads.example.com"));
+        assert!(!set.has_ancestor("other.com"));
+        assert!(set.has_ancestor("x.sub.other.com"));
+    }
+
+    #[test]
+    fn test_parents() {
+        assert_eq!(
+            parents("a.b.example.com").collect::<Vec<_>>(),
+            ["b.example.com", "example.com", "com"]
+        );
+        assert_eq!(parents("localhost").count(), 0);
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let mut set = DomainSet::new();
+        assert!(set.insert("example.com"));
+        assert!(!set.insert("example.com"));
+        assert_eq!(set.len(), 1);
+        assert!(set.remove("example.com"));
+        assert!(set.is_empty());
+        assert!(!set.covers("example.com"));
+    }
+}
@@ -0,0 +1,256 @@
+//! The comment header at the top of every generated file.
+
+/// Template of the header written at the top of every generated file.
+///
+/// `template` may use these placeholders:
+///
+/// | Placeholder         | Expands to                                        |
+/// |---------------------|---------------------------------------------------|
+/// | `{title}`           | [`HeaderTemplate::title`]                         |
+/// | `{url}`             | [`HeaderTemplate::url`]                           |
+/// | `{homepage}`        | [`HeaderTemplate::homepage`]                      |
+/// | `{license}`         | [`HeaderTemplate::license`]                       |
+/// | `{sources_heading}` | [`HeaderTemplate::sources_heading`]               |
+/// | `{category}`        | category of a per-category file, or nothing       |
+/// | `{category_line}`   | `Category: <category>`, or nothing                |
+/// | `{timestamp}`       | build time, e.g. `2025-01-31 04:00:00 UTC`        |
+/// | `{sources}`         | one `- <url>` line per configured source          |
+/// | `{total}`           | number of domains in the output                   |
+/// | `{pruned}`          | number of redundant subdomains pruned             |
+/// | `{pruned_line}`     | `Redundant subdomains pruned: N`, or nothing if 0 |
+/// | `{source_counts}`   | one `- <file> --> N unique domains (...)` line per section |
+///
+/// Placeholders are expanded in a single pass, so a value containing
+/// `{...}` (a title such as `{total} domains`) is written as it is. A line
+/// holding only a placeholder that expands to nothing is dropped. Every line
+/// of the result that doesn't start with `#` gets a `# ` prefix, so the
+/// header stays a comment in every output format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderTemplate {
+    pub template: String,
+    pub title: String,
+    /// Where the published list can be downloaded from.
+    pub url: String,
+    pub homepage: String,
+    pub license: String,
+    /// Line introducing the list of sources.
+    pub sources_heading: String,
+}
+
+/// The header this project has always written.
+pub const DEFAULT_HEADER_TEMPLATE: &str = "\
+# Title: {title}
+{category_line}
+#
+# URL to add in RouterOS:
+# {url}
+#
+# Homepage: {homepage}
+# License: {license}
+#
+# Last modified: {timestamp}
+#
+{sources_heading}
+{sources}
+#
+# Total unique domains: {total}
+{pruned_line}
+{source_counts}
+#
+";
+
+impl Default for HeaderTemplate {
+    fn default() -> Self {
+        HeaderTemplate {
+            template: DEFAULT_HEADER_TEMPLATE.to_string(),
+            title: "Unified DNS blocklist optimized for RouterOS, compiled from Hagezi sources"
+                .to_string(),
+            url: "https://raw.githubusercontent.com/eugenescodes/adblock2mikrotik_rust/refs/heads/main/hosts.txt"
+                .to_string(),
+            homepage: "https://github.com/eugenescodes/adblock2mikrotik_rust".to_string(),
+            license: "https://github.com/eugenescodes/adblock2mikrotik_rust/blob/main/LICENSE"
+                .to_string(),
+            sources_heading:
+                "This filter is generated using the following Hagezi DNS blocklist sources:"
+                    .to_string(),
+        }
+    }
+}
+
+/// Build-specific values substituted into a [`HeaderTemplate`].
+pub(crate) struct HeaderValues {
+    /// Set in the header of a per-category file.
+    pub(crate) category: Option<String>,
+    pub(crate) timestamp: String,
+    pub(crate) sources: Vec<String>,
+    pub(crate) total: usize,
+    pub(crate) pruned: usize,
+    pub(crate) source_counts: Vec<String>,
+}
+
+impl HeaderTemplate {
+    pub(crate) fn render(&self, values: &HeaderValues) -> String {
+        let pruned_line = if values.pruned > 0 {
+            format!("Redundant subdomains pruned: {}", values.pruned)
+        } else {
+            String::new()
+        };
+        let category = values.category.clone().unwrap_or_default();
+        let category_line = if category.is_empty() {
+            String::new()
+        } else {
+            format!("Category: {category}")
+        };
+        let list = |lines: &[String]| -> String {
+            lines
+                .iter()
+                .map(|line| format!("- {line}"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let placeholders = [
+            ("{title}", self.title.clone()),
+            ("{url}", self.url.clone()),
+            ("{homepage}", self.homepage.clone()),
+            ("{category}", category),
+            ("{category_line}", category_line),
+            ("{license}", self.license.clone()),
+            ("{sources_heading}", self.sources_heading.clone()),
+            ("{timestamp}", values.timestamp.clone()),
+            ("{sources}", list(&values.sources)),
+            ("{total}", values.total.to_string()),
+            ("{pruned}", values.pruned.to_string()),
+            ("{pruned_line}", pruned_line),
+            ("{source_counts}", list(&values.source_counts)),
+        ];
+
+        let mut header = String::with_capacity(self.template.len() * 2);
+        for line in self.template.lines() {
+            let trimmed = line.trim();
+            if placeholders
+                .iter()
+                .any(|(placeholder, value)| trimmed == *placeholder && value.is_empty())
+            {
+                continue;
+            }
+            let line = expand(line, &placeholders);
+            if line.is_empty() {
+                header.push_str("#\n");
+                continue;
+            }
+            for expanded in line.lines() {
+                if expanded.starts_with('#') {
+                    header.push_str(expanded);
+                } else if expanded.is_empty() {
+                    header.push('#');
+                } else {
+                    header.push_str("# ");
+                    header.push_str(expanded);
+                }
+                header.push('\n');
+            }
+        }
+        header
+    }
+}
+
+/// Replaces every known `{placeholder}` in `line` with its value, scanning
+/// the line once so that values are never expanded again. Other `{` are
+/// kept as they are.
+fn expand(line: &str, placeholders: &[(&str, String)]) -> String {
+    let mut expanded = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match placeholders
+            .iter()
+            .find(|(placeholder, _)| rest.starts_with(placeholder))
+        {
+            Some((placeholder, value)) => {
+                expanded.push_str(value);
+                rest = &rest[placeholder.len()..];
+            }
+            None => {
+                expanded.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(pruned: usize) -> HeaderValues {
+        HeaderValues {
+            category: None,
+            timestamp: "2025-01-31 04:00:00 UTC".to_string(),
+            sources: vec!["https://example.com/a.txt".to_string()],
+            total: 2,
+            pruned,
+            source_counts: vec!["a.txt --> 2 unique domains".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_default_template_matches_historical_header() {
+        let header = HeaderTemplate::default().render(&values(0));
+        assert!(header.starts_with(
+            "# Title: Unified DNS blocklist optimized for RouterOS, compiled from Hagezi sources\n#\n"
+        ));
+        assert!(header.contains(
+            "# This filter is generated using the following Hagezi DNS blocklist sources:\n\
+             # - https://example.com/a.txt\n\
+             #\n\
+             # Total unique domains: 2\n\
+             # - a.txt --> 2 unique domains\n\
+             #\n"
+        ));
+        assert!(header.ends_with("# Total unique domains: 2\n# - a.txt --> 2 unique domains\n#\n"));
+
+        let header = HeaderTemplate::default().render(&values(5));
+        assert!(header.contains("# Total unique domains: 2\n# Redundant subdomains pruned: 5\n"));
+
+        let header = HeaderTemplate::default().render(&HeaderValues {
+            category: Some("ads".to_string()),
+            ..values(0)
+        });
+        assert!(header.contains("Hagezi sources\n# Category: ads\n#\n"));
+    }
+
+    #[test]
+    fn test_custom_template_gets_comment_prefix() {
+        let template = HeaderTemplate {
+            template: "My list ({total} domains)\n\nBuilt {timestamp} from:\n{sources}\n"
+                .to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            template.render(&values(0)),
+            "# My list (2 domains)\n#\n# Built 2025-01-31 04:00:00 UTC from:\n# - https://example.com/a.txt\n"
+        );
+    }
+
+    #[test]
+    fn test_values_are_not_expanded_again() {
+        let template = HeaderTemplate {
+            template: "{title} ({total}) {unknown}\n{sources_heading}\n{sources}\n".to_string(),
+            title: "{total} domains".to_string(),
+            sources_heading: String::new(),
+            ..Default::default()
+        };
+        let header = template.render(&HeaderValues {
+            sources: vec!["https://example.com/{url}".to_string()],
+            ..values(0)
+        });
+        // The empty heading drops its line
+        assert_eq!(
+            header,
+            "# {total} domains (2) {unknown}\n# - https://example.com/{url}\n"
+        );
+    }
+}
@@ -1,11 +1,103 @@
 use anyhow::{Context, Result};
 use chrono::Utc;
 use encoding_rs::UTF_8;
-use std::collections::HashSet;
-use std::path::PathBuf;
+use reqwest::StatusCode;
+use reqwest::header::{ETAG, HeaderName, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 
-/// Prefix used in every output entry. Length is used to extract the domain part.
-const ENTRY_PREFIX: &str = "0.0.0.0 ";
+mod cache;
+mod daemon;
+mod deploy;
+mod domain_set;
+mod header;
+mod lookup;
+mod output;
+mod report;
+mod serve;
+
+use cache::{CacheMeta, CachedBody};
+use header::HeaderValues;
+
+pub use cache::FetchCache;
+pub use daemon::{Schedule, run_daemon, run_daemon_with_options};
+pub use deploy::{DeployAction, DeployReport, Router};
+pub use domain_set::DomainSet;
+pub use header::{DEFAULT_HEADER_TEMPLATE, HeaderTemplate};
+pub use lookup::{LookupMatch, LookupReport, MatchKind, Override, lookup};
+pub use output::{
+    CategoryOptions, Compression, DirectorySink, FormatOutput, ListFormat, MemorySink, OutputSink,
+    RscMode, RscOptions, check_categories,
+};
+pub use report::{BuildReport, ReportFormat, SourceReport};
+pub use serve::{serve, serve_with_options};
+
+/// How each blocked domain is written to the hosts file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryFormat {
+    /// `<address> domain`, e.g. `0.0.0.0 example.com` or `:: example.com`.
+    Sinkhole(IpAddr),
+    /// Bare `domain` lines, for consumers that take a plain domain list.
+    DomainOnly,
+}
+
+impl Default for EntryFormat {
+    /// `0.0.0.0 domain`, the format RouterOS adlists have always received.
+    fn default() -> Self {
+        EntryFormat::Sinkhole(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    }
+}
+
+impl EntryFormat {
+    /// Text written before each domain: the address and a space, or nothing.
+    pub fn prefix(&self) -> String {
+        match self {
+            EntryFormat::Sinkhole(ip) => format!("{ip} "),
+            EntryFormat::DomainOnly => String::new(),
+        }
+    }
+
+    /// Formats a single output entry for `domain`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use adblock2mikrotik_rust::EntryFormat;
+    /// assert_eq!(EntryFormat::default().entry("example.com"), "0.0.0.0 example.com");
+    /// assert_eq!("ipv6".parse::<EntryFormat>().unwrap().entry("example.com"), ":: example.com");
+    /// assert_eq!(EntryFormat::DomainOnly.entry("example.com"), "example.com");
+    /// ```
+    pub fn entry(&self, domain: &str) -> String {
+        format!("{}{domain}", self.prefix())
+    }
+}
+
+impl FromStr for EntryFormat {
+    type Err = anyhow::Error;
+
+    /// Parses `ipv4` (0.0.0.0), `ipv6` (::), `none` (domain only) or any
+    /// IPv4/IPv6 address literal such as `127.0.0.1`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ipv4" => Ok(EntryFormat::Sinkhole(IpAddr::V4(Ipv4Addr::UNSPECIFIED))),
+            "ipv6" => Ok(EntryFormat::Sinkhole(IpAddr::V6(Ipv6Addr::UNSPECIFIED))),
+            "none" => Ok(EntryFormat::DomainOnly),
+            other => other
+                .parse::<IpAddr>()
+                .map(EntryFormat::Sinkhole)
+                .map_err(|_| {
+                    anyhow::anyhow!(
+                        "invalid sinkhole {other:?} (expected ipv4, ipv6, none or an IP address)"
+                    )
+                }),
+        }
+    }
+}
 
 /// Validates a domain label (single segment between dots).
 /// Rules: non-empty, max 63 chars, alphanumeric + hyphens, no leading/trailing hyphen.
@@ -18,7 +110,7 @@ fn is_valid_label(label: &str) -> bool {
 }
 
 /// Validates a domain without regex — replaces DOMAIN_RE.
-/// Equivalent to: ^(?:[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?\.)+[a-zA-Z]{2,}$
+/// Equivalent to: ^(?:[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?\.)+(?:[a-zA-Z]{2,}|xn--[a-zA-Z0-9-]+)$
 fn is_valid_domain(domain: &str) -> bool {
     // Single pass: validate all labels, track last one for TLD check
     let mut iter = domain.split('.');
@@ -41,13 +133,38 @@ fn is_valid_domain(domain: &str) -> bool {
         return false;
     }
 
-    // Last label is TLD: only ASCII alpha, at least 2 chars
+    // Last label is TLD: only ASCII alpha, at least 2 chars, or an
+    // internationalized TLD in its xn-- form (e.g. xn--p1ai for .рф)
     match prev {
+        Some(tld) if is_punycode_label(tld) => is_valid_label(tld),
         Some(tld) => tld.len() >= 2 && tld.chars().all(|c| c.is_ascii_alphabetic()),
         None => false,
     }
 }
 
+fn is_punycode_label(label: &str) -> bool {
+    label
+        .get(..4)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("xn--"))
+}
+
+/// Converts Unicode labels to their `xn--` ASCII form (IDNA / UTS #46), so
+/// internationalized domains can be validated and written like any other,
+/// then validates the result. Domains are lowercased on the way, since DNS
+/// names compare case-insensitively and every later step (deduplication,
+/// exceptions, subdomain pruning) compares them exactly. Already-lowercase
+/// ASCII domains are borrowed as they are.
+fn ascii_domain(domain: &str) -> Option<Cow<'_, str>> {
+    let domain = if !domain.is_ascii() {
+        Cow::Owned(idna::domain_to_ascii(domain).ok()?)
+    } else if domain.bytes().any(|b| b.is_ascii_uppercase()) {
+        Cow::Owned(domain.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(domain)
+    };
+    is_valid_domain(&domain).then_some(domain)
+}
+
 /// Converts an adblock rule to a hosts file entry, or returns None if invalid.
 /// Uses manual string parsing instead of regex for better performance on large inputs.
 ///
@@ -67,64 +184,475 @@ fn is_valid_domain(domain: &str) -> bool {
 /// assert_eq!(convert_rule("||invalid_domain^"), None);
 /// ```
 pub fn convert_rule(rule: &str) -> Option<String> {
-    // Strip inline comment without allocation (replaces COMMENT_RE.replace())
-    let rule = match rule.find('#') {
-        Some(pos) => rule[..pos].trim(),
-        None => rule.trim(),
-    };
+    convert_rule_with(rule, &EntryFormat::default())
+}
+
+/// Like [`convert_rule`], but writes the entry in the given format.
+///
+/// # Examples
+///
+/// ```
+/// use adblock2mikrotik_rust::{EntryFormat, convert_rule_with};
+/// let format: EntryFormat = "127.0.0.1".parse().unwrap();
+/// assert_eq!(convert_rule_with("||example.com^", &format), Some("127.0.0.1 example.com".to_string()));
+/// assert_eq!(convert_rule_with("||example.com^", &EntryFormat::DomainOnly), Some("example.com".to_string()));
+/// ```
+pub fn convert_rule_with(rule: &str, format: &EntryFormat) -> Option<String> {
+    let domain = parse_adblock_rule(rule)?;
+    Some(format.entry(&domain))
+}
+
+/// Extracts the blocked domain from an `||domain^` adblock rule.
+fn parse_adblock_rule(rule: &str) -> Option<Cow<'_, str>> {
+    let rule = strip_comment(rule);
 
     if rule.is_empty() {
         return None;
     }
 
     // Must start with "||" — strip_prefix returns None otherwise
-    let rest = rule.strip_prefix("||")?;
+    pattern_domain(rule.strip_prefix("||")?)
+}
 
-    // Take domain: up to first '^', then up to first '$' (for option modifiers)
-    let domain = rest.split('^').next()?.split('$').next()?;
+/// Hostnames found in the boilerplate of most hosts files that pass domain
+/// validation but must never be blocked.
+const HOSTS_BOILERPLATE: &[&str] = &["localhost.localdomain"];
 
-    if is_valid_domain(domain) {
-        Some(format!("{ENTRY_PREFIX}{domain}"))
-    } else {
-        None
+/// Extracts the domains from a hosts-file line such as `0.0.0.0 example.com`:
+/// every valid hostname after the address, as a line may list several
+/// aliases. Yields nothing if the line is not a valid hosts entry.
+///
+/// # Examples
+///
+/// ```
+/// use adblock2mikrotik_rust::parse_hosts_line;
+/// let domains = |line| parse_hosts_line(line).collect::<Vec<_>>();
+/// assert_eq!(domains("0.0.0.0 example.com"), ["example.com"]);
+/// assert_eq!(domains("127.0.0.1\tads.example.com # tracker"), ["ads.example.com"]);
+/// assert_eq!(domains(":: a.example.com b.example.com"), ["a.example.com", "b.example.com"]);
+/// // No address
+/// assert!(domains("example.com").is_empty());
+/// ```
+pub fn parse_hosts_line(line: &str) -> impl Iterator<Item = Cow<'_, str>> {
+    let mut fields = strip_comment(line).split_whitespace();
+    let has_address = fields
+        .next()
+        .is_some_and(|field| field.parse::<IpAddr>().is_ok());
+    has_address
+        .then_some(fields)
+        .into_iter()
+        .flatten()
+        .filter_map(ascii_domain)
+        .filter(|domain| !HOSTS_BOILERPLATE.contains(&domain.as_ref()))
+}
+
+/// Extracts the domain from a plain one-domain-per-line entry, or returns
+/// None if invalid. A leading `*.` wildcard, used by some domain lists to
+/// mean "this domain and its subdomains", is stripped.
+///
+/// # Examples
+///
+/// ```
+/// use adblock2mikrotik_rust::parse_domain_line;
+/// assert_eq!(parse_domain_line("example.com").as_deref(), Some("example.com"));
+/// assert_eq!(parse_domain_line("*.example.com # wildcard").as_deref(), Some("example.com"));
+/// assert_eq!(parse_domain_line("||example.com^"), None);
+/// ```
+pub fn parse_domain_line(line: &str) -> Option<Cow<'_, str>> {
+    let line = strip_comment(line);
+    ascii_domain(line.strip_prefix("*.").unwrap_or(line))
+}
+
+/// Syntax of a source list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceFormat {
+    /// AdBlock-style `||domain^` rules, with `@@||domain^` exceptions.
+    Adblock,
+    /// Hosts file: `0.0.0.0 domain` (any IPv4/IPv6 address).
+    Hosts,
+    /// One plain domain per line.
+    Domains,
+}
+
+/// Number of leading lines inspected by [`SourceFormat::detect`].
+const DETECT_SAMPLE_LINES: usize = 200;
+
+impl SourceFormat {
+    /// Guesses the format of a list from its first lines, as returned by
+    /// [`fetch_rules`]. Each sampled line votes for the format it looks like;
+    /// ties fall back to adblock, the historical (and only) format.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use adblock2mikrotik_rust::SourceFormat;
+    /// let lines = |s: &str| s.lines().map(String::from).collect::<Vec<_>>();
+    /// assert_eq!(SourceFormat::detect(&lines("||a.com^\n||b.com^")), SourceFormat::Adblock);
+    /// assert_eq!(SourceFormat::detect(&lines("0.0.0.0 a.com\n0.0.0.0 b.com")), SourceFormat::Hosts);
+    /// assert_eq!(SourceFormat::detect(&lines("a.com\nb.com")), SourceFormat::Domains);
+    /// ```
+    pub fn detect(rules: &[String]) -> SourceFormat {
+        Self::detect_lines(rules.iter().map(String::as_str))
+    }
+
+    /// [`detect`](SourceFormat::detect) for lines borrowed from a list.
+    pub(crate) fn detect_lines<'a>(lines: impl IntoIterator<Item = &'a str>) -> SourceFormat {
+        let (mut adblock, mut hosts, mut domains) = (0usize, 0usize, 0usize);
+        for line in lines.into_iter().take(DETECT_SAMPLE_LINES) {
+            let line = line.trim_start();
+            // "!" comments and "[Adblock Plus]" headers only appear in adblock lists
+            if line.starts_with("||")
+                || line.starts_with("@@")
+                || line.starts_with('!')
+                || line.starts_with('[')
+            {
+                adblock += 1;
+            } else if line
+                .split_whitespace()
+                .next()
+                .is_some_and(|field| field.parse::<IpAddr>().is_ok())
+            {
+                hosts += 1;
+            } else if parse_domain_line(line).is_some() {
+                domains += 1;
+            }
+        }
+
+        if hosts > adblock && hosts >= domains {
+            SourceFormat::Hosts
+        } else if domains > adblock && domains > hosts {
+            SourceFormat::Domains
+        } else {
+            SourceFormat::Adblock
+        }
+    }
+
+    /// Extracts the blocked domains from one line of a list in this format:
+    /// at most one, except for hosts lines listing several hostnames.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use adblock2mikrotik_rust::SourceFormat;
+    /// let domains = |format: SourceFormat, line| format.parse_line(line).collect::<Vec<_>>();
+    /// assert_eq!(domains(SourceFormat::Adblock, "||example.com^"), ["example.com"]);
+    /// assert_eq!(domains(SourceFormat::Hosts, "0.0.0.0 example.com www.example.com"), ["example.com", "www.example.com"]);
+    /// assert_eq!(domains(SourceFormat::Domains, "example.com"), ["example.com"]);
+    /// ```
+    pub fn parse_line(self, line: &str) -> impl Iterator<Item = Cow<'_, str>> {
+        let (single, hosts) = match self {
+            SourceFormat::Adblock => (parse_adblock_rule(line), None),
+            SourceFormat::Hosts => (None, Some(parse_hosts_line(line))),
+            SourceFormat::Domains => (parse_domain_line(line), None),
+        };
+        single.into_iter().chain(hosts.into_iter().flatten())
+    }
+}
+
+impl fmt::Display for SourceFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SourceFormat::Adblock => "adblock",
+            SourceFormat::Hosts => "hosts",
+            SourceFormat::Domains => "domains",
+        })
+    }
+}
+
+/// One source list to fetch, with its per-source settings. Converts from a
+/// bare URL, which gets the defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Source {
+    /// HTTP(S) URL, `file://` URL or filesystem path, or `-` for stdin.
+    pub url: String,
+    /// Shown in the console output and the header instead of the last
+    /// segment of the URL.
+    pub name: Option<String>,
+    /// Skips format detection, for lists it would guess wrong.
+    pub format: Option<SourceFormat>,
+    /// Free-form label, e.g. "ads" or "malware", copied to the report.
+    pub category: Option<String>,
+    /// Fail the build, without writing anything, if this source can't be
+    /// fetched (nor served from the cache). Other sources are skipped
+    /// with a warning when they fail.
+    pub required: bool,
+}
+
+impl Source {
+    pub fn new(url: impl Into<String>) -> Self {
+        Source {
+            url: url.into(),
+            ..Default::default()
+        }
+    }
+
+    /// The configured name, or the last segment of the URL or path
+    /// (matches Python's url.split('/')[-1]), so long URLs don't clutter
+    /// the output.
+    pub fn short_name(&self) -> &str {
+        match &self.name {
+            Some(name) => name,
+            None if self.url == "-" => "stdin",
+            None => self.url.split(['/', '\\']).next_back().unwrap_or(&self.url),
+        }
+    }
+}
+
+impl From<&str> for Source {
+    fn from(url: &str) -> Self {
+        Source::new(url)
+    }
+}
+
+impl From<String> for Source {
+    fn from(url: String) -> Self {
+        Source::new(url)
+    }
+}
+
+impl From<&String> for Source {
+    fn from(url: &String) -> Self {
+        Source::new(url.as_str())
+    }
+}
+
+/// Extracts the domain from an `@@||domain^` exception (allowlist) rule, or
+/// returns None if the line is not a valid exception rule.
+///
+/// An exception unblocks the domain itself and every subdomain of it, so
+/// `@@||example.com^` also cancels `||ads.example.com^`.
+///
+/// Only exceptions that apply to DNS are honored: those without modifiers or
+/// with just `$important` and `$document`. One limited to some pages
+/// (`$domain=`), request types (`$script`) or that cancels another rule
+/// (`$badfilter`) returns None, as a DNS blocklist can't express it.
+///
+/// # Examples
+///
+/// ```
+/// use adblock2mikrotik_rust::parse_exception;
+/// assert_eq!(parse_exception("@@||example.com^").as_deref(), Some("example.com"));
+/// assert_eq!(parse_exception("@@||example.com^$important # comment").as_deref(), Some("example.com"));
+/// assert_eq!(parse_exception("@@||example.com^$domain=example.org"), None);
+/// // Blocking rules are not exceptions
+/// assert_eq!(parse_exception("||example.com^"), None);
+/// ```
+pub fn parse_exception(rule: &str) -> Option<Cow<'_, str>> {
+    let rest = strip_comment(rule).strip_prefix("@@||")?;
+    if let Some((_, modifiers)) = rest.split_once('$')
+        && !modifiers
+            .split(',')
+            .all(|m| matches!(m.trim(), "important" | "document"))
+    {
+        return None;
+    }
+    pattern_domain(rest)
+}
+
+/// Strips an inline `#` comment and surrounding whitespace without allocation
+/// (replaces COMMENT_RE.replace()).
+fn strip_comment(rule: &str) -> &str {
+    match rule.find('#') {
+        Some(pos) => rule[..pos].trim(),
+        None => rule.trim(),
     }
 }
 
+/// Takes the domain from the part of a rule following `||`: up to first '^',
+/// then up to first '$' (for option modifiers). Returns None if the result
+/// is not a valid domain.
+fn pattern_domain(rest: &str) -> Option<Cow<'_, str>> {
+    ascii_domain(rest.split('^').next()?.split('$').next()?)
+}
+
+/// Fetches a list and splits it into candidate rules. Besides HTTP(S) URLs,
+/// `url` may be a `file://` URL, a filesystem path, or `-` for stdin.
+/// Retries are printed to stdout, like [`run_with_options`] does.
 pub async fn fetch_rules(client: &reqwest::Client, url: &str) -> Result<Vec<String>> {
+    fetch_rules_cached(client, url, None).await
+}
+
+/// Like [`fetch_rules`], but with a [`FetchCache`]: the request carries
+/// `If-None-Match` / `If-Modified-Since` from the cached copy, and an HTTP 304
+/// reuses the cached body instead of downloading the list again.
+pub async fn fetch_rules_cached(
+    client: &reqwest::Client,
+    url: &str,
+    cache: Option<&FetchCache>,
+) -> Result<Vec<String>> {
+    let console = Console {
+        logger: Some(Arc::new(print_logger(Verbosity::Normal))),
+    };
+    let bytes = fetch_body(client, url, cache, &console).await?;
+    Ok(body_to_rules(&bytes))
+}
+
+/// Splits a raw list body into candidate rules.
+fn body_to_rules(bytes: &[u8]) -> Vec<String> {
+    // Use encoding_rs to decode. It handles BOM and replaces invalid characters
+    // with the replacement character () instead of panicking.
+    let (decoded_cow, _had_errors) = UTF_8.decode_with_bom_removal(bytes);
+
+    // Filter before allocating: skip empty and comment-only lines early
+    decoded_cow
+        .lines()
+        .filter(|line| {
+            let t = line.trim_start();
+            !t.is_empty() && !t.starts_with('#')
+        })
+        .map(|line| line.trim().to_string())
+        .collect()
+}
+
+/// Largest list accepted after decompression, so a small malicious archive
+/// can't exhaust memory.
+const MAX_DECOMPRESSED_BYTES: u64 = 1 << 30;
+
+/// Decompresses a gzip or zstd list body, recognized by its magic bytes.
+/// `Content-Encoding` and `Content-Type` are not trusted: servers send
+/// `gzip` for bodies that aren't (or that a proxy already decoded), and
+/// those are used as they are. A `.gz` file served with `Content-Encoding:
+/// gzip` is compressed twice, so the result is checked once more.
+fn decompress_body(mut body: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    for _ in 0..2 {
+        let Some(compression) = Compression::sniff(&body) else {
+            break;
+        };
+        body = compression.decompress(&body, MAX_DECOMPRESSED_BYTES)?;
+    }
+    Ok(body)
+}
+
+/// A source read from the local machine instead of over HTTP.
+#[derive(Debug, PartialEq)]
+enum LocalSource {
+    Stdin,
+    File(PathBuf),
+}
+
+impl LocalSource {
+    /// `-` is stdin; a `file://` URL or anything without a `scheme://` is a
+    /// filesystem path. Returns None for other URLs.
+    fn parse(url: &str) -> Option<LocalSource> {
+        if url == "-" {
+            return Some(LocalSource::Stdin);
+        }
+        if let Some(rest) = url.strip_prefix("file://") {
+            // Url decodes %20 and friends; `file://relative/path` has a
+            // host, which Url refuses, so it is taken literally
+            let path = reqwest::Url::parse(url)
+                .ok()
+                .and_then(|url| url.to_file_path().ok())
+                .unwrap_or_else(|| PathBuf::from(rest));
+            return Some(LocalSource::File(path));
+        }
+        (!url.contains("://")).then(|| LocalSource::File(PathBuf::from(url)))
+    }
+
+    async fn read(&self) -> std::io::Result<Vec<u8>> {
+        match self {
+            LocalSource::Stdin => {
+                use tokio::io::AsyncReadExt;
+                let mut body = Vec::new();
+                tokio::io::stdin().read_to_end(&mut body).await?;
+                Ok(body)
+            }
+            LocalSource::File(path) => tokio::fs::read(path).await,
+        }
+    }
+}
+
+/// Downloads the raw body of a list, revalidating the cached copy if any.
+/// Local files and stdin are read directly, without cache or retries.
+async fn fetch_body(
+    client: &reqwest::Client,
+    url: &str,
+    cache: Option<&FetchCache>,
+    console: &Console,
+) -> Result<Vec<u8>> {
+    if let Some(local) = LocalSource::parse(url) {
+        let body = local
+            .read()
+            .await
+            .with_context(|| format!("Failed to read {url}"))?;
+        return decompress_body(body).with_context(|| format!("Failed to decompress {url}"));
+    }
+    let cached = match cache {
+        Some(cache) => cache.load(url).await,
+        None => None,
+    };
+    if let Some(cached) = &cached {
+        console.detail(format_args!(
+            "Revalidating cached copy of {url} (ETag: {}, Last-Modified: {})",
+            cached.meta.etag.as_deref().unwrap_or("none"),
+            cached.meta.last_modified.as_deref().unwrap_or("none")
+        ));
+    }
+
     // Retry-logic: 3 attempts with exponential backoff: 2s → 4s (no wait after final attempt)
     let max_attempts = 3;
     let mut last_error: Option<anyhow::Error> = None;
 
     for attempt in 0..max_attempts {
-        let result = client
-            .get(url)
+        let mut request = client.get(url);
+        if let Some(cached) = &cached {
+            if let Some(etag) = &cached.meta.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cached.meta.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let result = request
             .send()
             .await
             .with_context(|| format!("Failed to send request to {}", url));
 
         match result {
+            Ok(response) if response.status() == StatusCode::NOT_MODIFIED && cached.is_some() => {
+                let CachedBody { mut meta, body } = cached.expect("checked above");
+                console.info(format_args!(
+                    "Not modified since last fetch, using cached copy of {url}"
+                ));
+                meta.fetched_at = Utc::now().timestamp();
+                if let Some(cache) = cache
+                    && let Err(e) = cache.store_meta(&meta).await
+                {
+                    console.warn(format_args!("failed to update cache for {url}: {e}"));
+                }
+                return Ok(body);
+            }
             Ok(response) if response.status().is_success() => {
+                let header = |name: HeaderName| {
+                    response
+                        .headers()
+                        .get(name)
+                        .and_then(|v| v.to_str().ok())
+                        .map(str::to_string)
+                };
+                let meta = CacheMeta {
+                    url: url.to_string(),
+                    etag: header(ETAG),
+                    last_modified: header(LAST_MODIFIED),
+                    fetched_at: Utc::now().timestamp(),
+                };
                 // Get raw bytes instead of text() to handle encoding manually
                 let bytes = response
                     .bytes()
                     .await
                     .with_context(|| format!("Failed to read response bytes from {}", url))?;
+                // Decompressed before caching, so the cached copy is plain
+                // text whatever the mirror sent
+                let bytes = decompress_body(bytes.to_vec())
+                    .with_context(|| format!("Failed to decompress {url}"))?;
 
-                // Use encoding_rs to decode. It handles BOM and replaces invalid characters
-                // with the replacement character () instead of panicking.
-                let (decoded_cow, _had_errors) = UTF_8.decode_with_bom_removal(&bytes);
-
-                // Filter before allocating: skip empty and comment-only lines early
-                let rules: Vec<String> = decoded_cow
-                    .lines()
-                    .filter(|line| {
-                        let t = line.trim_start();
-                        !t.is_empty() && !t.starts_with('#')
-                    })
-                    .map(|line| line.trim().to_string())
-                    .collect();
-
-                return Ok(rules);
+                if let Some(cache) = cache
+                    && let Err(e) = cache.store(&meta, &bytes).await
+                {
+                    console.warn(format_args!("failed to cache {url}: {e}"));
+                }
+                return Ok(bytes);
             }
             Ok(response) => {
                 last_error = Some(anyhow::anyhow!(
@@ -140,12 +668,12 @@ pub async fn fetch_rules(client: &reqwest::Client, url: &str) -> Result<Vec<Stri
 
         if attempt < max_attempts - 1 {
             let wait_secs = 2u64.pow(attempt as u32 + 1); // 2s, then 4s
-            println!(
+            console.info(format_args!(
                 "Attempt {} failed for {}. Retrying in {}s...",
                 attempt + 1,
                 url,
                 wait_secs
-            );
+            ));
             tokio::time::sleep(std::time::Duration::from_secs(wait_secs)).await;
         }
     }
@@ -155,6 +683,95 @@ pub async fn fetch_rules(client: &reqwest::Client, url: &str) -> Result<Vec<Stri
     }))
 }
 
+/// Reads the `# Total unique domains:` trailer of a previously written
+/// hosts or index file, or None if it has no trailer.
+fn previous_total(content: &str) -> Option<usize> {
+    content
+        .lines()
+        .rev()
+        .find_map(|line| line.strip_prefix("# Total unique domains: "))
+        .and_then(|n| n.trim().parse().ok())
+}
+
+/// Relative change from `previous` to `current`, in percent.
+fn change_percent(previous: usize, current: usize) -> f64 {
+    if previous == 0 {
+        return if current == 0 { 0.0 } else { f64::INFINITY };
+    }
+    previous.abs_diff(current) as f64 * 100.0 / previous as f64
+}
+
+/// Raw body of one source, with the age in seconds of the cached copy it
+/// came from when the live fetch failed.
+struct FetchedSource {
+    body: Vec<u8>,
+    stale_age: Option<u64>,
+}
+
+/// Fetches a source for [`run_with_options`]. When every attempt fails, falls
+/// back to the last successfully downloaded body in the cache, as long as it
+/// is at most `max_staleness` old: a briefly unreachable list then keeps its
+/// domains blocked instead of vanishing from the output until the next run.
+async fn fetch_source(
+    client: &reqwest::Client,
+    url: &str,
+    cache: Option<&FetchCache>,
+    max_staleness: std::time::Duration,
+    console: &Console,
+) -> Result<FetchedSource> {
+    let error = match fetch_body(client, url, cache, console).await {
+        Ok(body) => {
+            return Ok(FetchedSource {
+                body,
+                stale_age: None,
+            });
+        }
+        Err(e) => e,
+    };
+    let Some(cache) = cache else {
+        console.warn(format_args!(
+            "No download cache is configured, so {url} has no last-known-good copy to fall back on"
+        ));
+        return Err(error);
+    };
+    let Some(cached) = cache.load(url).await else {
+        return Err(error);
+    };
+
+    let age = Utc::now()
+        .timestamp()
+        .saturating_sub(cached.meta.fetched_at)
+        .max(0) as u64;
+    if age > max_staleness.as_secs() {
+        return Err(anyhow::anyhow!(
+            "{error} (cached copy from {} ago exceeds the maximum staleness of {})",
+            format_age(age),
+            format_age(max_staleness.as_secs())
+        ));
+    }
+    console.warn(format_args!(
+        "Failed to fetch rules from {url}: {error}. Using last-known-good cached copy from {} ago",
+        format_age(age)
+    ));
+    Ok(FetchedSource {
+        body: cached.body,
+        stale_age: Some(age),
+    })
+}
+
+/// Formats a duration in seconds as its two most significant units, e.g.
+/// "2d 3h", "5h 12m" or "7m".
+fn format_age(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86_400, secs % 86_400 / 3_600, secs % 3_600 / 60);
+    if days > 0 {
+        format!("{days}d {hours}h")
+    } else if hours > 0 {
+        format!("{hours}h {minutes}m")
+    } else {
+        format!("{minutes}m")
+    }
+}
+
 // Helper function to format numbers with commas (e.g., 76376 -> "76,376")
 fn format_with_commas(n: usize) -> String {
     let n_str = n.to_string();
@@ -168,189 +785,1243 @@ fn format_with_commas(n: usize) -> String {
     result.chars().rev().collect()
 }
 
-pub async fn run(urls: Vec<&str>) -> std::io::Result<()> {
-    let start_time = std::time::Instant::now();
+/// How much progress output [`run_with_options`] prints to stdout. Warnings
+/// always go to stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Verbosity {
+    /// Warnings only.
+    Quiet,
+    /// Progress and summary, as printed historically.
+    #[default]
+    Normal,
+    /// Also per-source details such as cache revalidation and skipped lines.
+    Verbose,
+}
 
-    // Create a single Client instance to reuse connections (Keep-Alive)
-    let client = reqwest::Client::builder()
-        .connect_timeout(std::time::Duration::from_secs(3))
-        .build()
-        .expect("Failed to build reqwest client");
+/// Importance of a message passed to a [`Converter::logger`] hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    /// Something was skipped or degraded, e.g. a source fell back to its
+    /// cached copy or a local list entry was invalid.
+    Warning,
+    /// Progress and summary lines.
+    Info,
+    /// Per-source details.
+    Detail,
+}
+
+/// Logging hook of a [`Converter`].
+pub type Logger = Arc<dyn Fn(LogLevel, &str) + Send + Sync>;
 
-    // HashSet<String> stores domain strings for uniqueness checking
-    // Pre-allocate for expected ~300k domains to avoid rehashing
-    let mut seen_domains: HashSet<String> = HashSet::with_capacity(300_000);
-    let mut source_data: Vec<(String, Vec<String>)> = Vec::new();
+/// Routes progress messages to the logging hook, if any. Without one,
+/// messages are dropped: the library itself never prints.
+#[derive(Clone, Default)]
+struct Console {
+    logger: Option<Logger>,
+}
 
-    println!("Starting conversion of {} source(s)...\n", urls.len());
+impl Console {
+    fn log(&self, level: LogLevel, message: impl fmt::Display) {
+        if let Some(logger) = &self.logger {
+            logger(level, &message.to_string());
+        }
+    }
+
+    fn warn(&self, message: impl fmt::Display) {
+        self.log(LogLevel::Warning, message);
+    }
 
+    fn info(&self, message: impl fmt::Display) {
+        self.log(LogLevel::Info, message);
+    }
+
+    fn detail(&self, message: impl fmt::Display) {
+        self.log(LogLevel::Detail, message);
+    }
+}
+
+/// The logger used by [`run_with_options`] and [`lookup`].
+fn print_logger(verbosity: Verbosity) -> impl Fn(LogLevel, &str) + Send + Sync + 'static {
+    move |level, message| match level {
+        LogLevel::Warning => eprintln!("Warning: {message}"),
+        LogLevel::Info if verbosity >= Verbosity::Normal => println!("{message}"),
+        LogLevel::Detail if verbosity >= Verbosity::Verbose => println!("{message}"),
+        _ => {}
+    }
+}
+
+/// Options for [`run_with_options`] beyond the list of source URLs.
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    /// Local allowlist: plain domains (one per entry, `#` comments allowed)
+    /// removed from every source. Like an exception rule, an entry also
+    /// covers all of its subdomains.
+    pub allowlist: Vec<String>,
+    /// Local denylist: plain domains always blocked, emitted under their own
+    /// `# Source: local denylist` section. Upstream exception rules do not
+    /// apply to them; the local allowlist does.
+    pub denylist: Vec<String>,
+    /// Drop domains whose parent domain is also blocked (e.g.
+    /// `ads.example.com` when `example.com` is listed), since RouterOS adlist
+    /// entries already match every subdomain. Enabled by default.
+    pub prune_subdomains: bool,
+    /// Also write a RouterOS `.rsc` import script of `/ip dns static`
+    /// records, for routers without `/ip/dns/adlist` (before 7.15) or
+    /// provisioned offline. None (the default) writes hosts.txt only.
+    pub rsc: Option<RscOptions>,
+    /// Extra list files in other resolvers' formats (dnsmasq, Unbound, RPZ,
+    /// AdGuard Home), each holding every domain of the combined hosts.txt.
+    pub formats: Vec<FormatOutput>,
+    /// Also write these compressed copies of every generated file, e.g.
+    /// hosts.txt.gz next to hosts.txt. `serve` sends them to clients
+    /// accepting the encoding. Empty (the default) writes none.
+    pub compression: Vec<Compression>,
+    /// Also write one hosts file per [`Source::category`], e.g.
+    /// hosts-ads.txt, so routers can load only some categories. None (the
+    /// default) writes the combined hosts.txt only.
+    pub categories: Option<CategoryOptions>,
+    /// Split the hosts list into `hosts-1.txt`, `hosts-2.txt`, … of at most
+    /// this many bytes each, plus a `hosts-index.txt` listing the parts,
+    /// instead of a single hosts.txt. For routers with little storage.
+    pub max_bytes_per_file: Option<usize>,
+    /// Like `max_bytes_per_file`, but caps the number of entries per part.
+    /// Both limits may be combined.
+    pub max_entries_per_file: Option<usize>,
+    /// How each entry is written: sinkhole address or bare domain. The
+    /// default, `0.0.0.0 domain`, matches what RouterOS adlists expect.
+    pub entry_format: EntryFormat,
+    /// Directory for the conditional-fetch cache. When set, each source's
+    /// last body is kept with its ETag / Last-Modified, and unchanged lists
+    /// are not downloaded again. None (the default) disables caching, and
+    /// with it the stale-copy fallback below; the CLI defaults to a private
+    /// per-user directory under `$XDG_CACHE_HOME` or `~/.cache`. Whoever can
+    /// write to this directory controls what gets published, so it must not
+    /// be shared with other users.
+    pub cache_dir: Option<PathBuf>,
+    /// When a source can't be fetched, its cached copy is used instead if it
+    /// is at most this old, and marked as stale in the header. Only applies
+    /// with `cache_dir` set. Defaults to 7 days.
+    pub max_staleness: std::time::Duration,
+    /// Abort without replacing the existing output when the total number of
+    /// domains shrank or grew by more than this percentage compared to the
+    /// `# Total unique domains:` trailer of the previous file. None (the
+    /// default) disables the check.
+    pub max_change_percent: Option<f64>,
+    /// Write the output even if it fails the `max_change_percent` check.
+    pub force: bool,
+    /// Directory the output is written to. None falls back to the
+    /// `OUTPUT_DIR` environment variable, then the working directory.
+    pub output_dir: Option<PathBuf>,
+    /// Fetch and convert everything, run the safety check, but write
+    /// nothing. Fails if any source could not be fetched or nothing was
+    /// converted, so it can gate a deployment.
+    pub dry_run: bool,
+    pub verbosity: Verbosity,
+    /// Also write a [`BuildReport`] in this format next to the output, even
+    /// when the build fails. A dry run only writes it to `report_path`.
+    pub report: Option<ReportFormat>,
+    /// Write the report to this file instead of next to the output.
+    pub report_path: Option<PathBuf>,
+    /// Comment header at the top of every generated file.
+    pub header: HeaderTemplate,
+    /// Routers to reload (or upload the list to) after each successful
+    /// build, through the RouterOS REST API.
+    pub routers: Vec<Router>,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions {
+            allowlist: Vec::new(),
+            denylist: Vec::new(),
+            prune_subdomains: true,
+            rsc: None,
+            formats: Vec::new(),
+            compression: Vec::new(),
+            categories: None,
+            max_bytes_per_file: None,
+            max_entries_per_file: None,
+            entry_format: EntryFormat::default(),
+            cache_dir: None,
+            max_staleness: std::time::Duration::from_secs(7 * 86_400),
+            max_change_percent: None,
+            force: false,
+            output_dir: None,
+            dry_run: false,
+            verbosity: Verbosity::default(),
+            report: None,
+            report_path: None,
+            header: HeaderTemplate::default(),
+            routers: Vec::new(),
+        }
+    }
+}
+
+/// Converted entries of one section of the output file, with the stats
+/// reported for it in the header.
+#[derive(Default)]
+struct SourceOutput {
+    url: String,
+    /// [`Source::name`], shown instead of the URL's last segment.
+    name: Option<String>,
+    /// [`Source::category`]; None for the local denylist.
+    category: Option<String>,
+    domains: Vec<String>,
+    removed_by_exceptions: usize,
+    removed_by_allowlist: usize,
+    pruned_subdomains: usize,
+    /// Subdomains pruned from `domains` only because their parent comes from
+    /// a source of another category: the category's own file keeps them.
+    category_only: Vec<String>,
+    /// Age in seconds of the cached copy used because the fetch failed.
+    stale_age: Option<u64>,
+}
+
+impl SourceOutput {
+    /// The section as written to its category file, with the subdomains
+    /// covered only from another category put back. None if it has none.
+    fn category_view(&self) -> Option<SourceOutput> {
+        if self.category_only.is_empty() {
+            return None;
+        }
+        Some(SourceOutput {
+            url: self.url.clone(),
+            name: self.name.clone(),
+            category: self.category.clone(),
+            domains: [&self.domains[..], &self.category_only[..]].concat(),
+            removed_by_exceptions: self.removed_by_exceptions,
+            removed_by_allowlist: self.removed_by_allowlist,
+            pruned_subdomains: self.pruned_subdomains - self.category_only.len(),
+            category_only: Vec::new(),
+            stale_age: self.stale_age,
+        })
+    }
+}
+
+/// Name of the generated hosts file inside the output directory.
+const HOSTS_FILE: &str = "hosts.txt";
+
+/// Label used for the section holding [`RunOptions::denylist`] entries.
+const LOCAL_DENYLIST: &str = "local denylist";
+
+/// Parses a local allowlist/denylist entry: a plain domain with an optional
+/// inline `#` comment. Returns None for empty, comment-only or invalid lines.
+fn parse_local_entry(line: &str) -> Option<Cow<'_, str>> {
+    ascii_domain(strip_comment(line))
+}
+
+/// Validates local allowlist/denylist entries, warning about invalid ones.
+fn collect_local_entries(entries: &[String], kind: &str, console: &Console) -> Vec<String> {
+    let mut domains = Vec::with_capacity(entries.len());
+    for line in entries {
+        match parse_local_entry(line) {
+            Some(domain) => domains.push(domain.to_string()),
+            None if strip_comment(line).is_empty() => {}
+            None => console.warn(format_args!(
+                "ignoring invalid {kind} entry: {}",
+                line.trim()
+            )),
+        }
+    }
+    domains
+}
+
+/// One `- <file> --> N unique domains (...)` header line per section.
+fn source_counts<'a>(sections: impl IntoIterator<Item = &'a SourceOutput>) -> Vec<String> {
+    sections
+        .into_iter()
+        .map(|source| {
+            let short = match &source.name {
+                Some(name) => name.as_str(),
+                None => source.url.split('/').next_back().unwrap_or(&source.url),
+            };
+            let total = source.domains.len();
+            let mut removed = Vec::new();
+            if let Some(age) = source.stale_age {
+                removed.push(format!("stale: cached copy from {} ago", format_age(age)));
+            }
+            if source.removed_by_exceptions > 0 {
+                removed.push(format!(
+                    "{} removed by exceptions",
+                    source.removed_by_exceptions
+                ));
+            }
+            if source.removed_by_allowlist > 0 {
+                removed.push(format!(
+                    "{} removed by allowlist",
+                    source.removed_by_allowlist
+                ));
+            }
+            if source.pruned_subdomains > 0 {
+                removed.push(format!(
+                    "{} redundant subdomains pruned",
+                    source.pruned_subdomains
+                ));
+            }
+            if removed.is_empty() {
+                format!("{short} --> {total} unique domains")
+            } else {
+                format!(
+                    "{short} --> {total} unique domains ({})",
+                    removed.join(", ")
+                )
+            }
+        })
+        .collect()
+}
+
+/// One hosts list to write: the combined hosts.txt or a category's file.
+struct HostsList<'a> {
+    file: String,
+    category: Option<&'a str>,
+    header: String,
+    sections: Vec<&'a SourceOutput>,
+    total: usize,
+}
+
+pub async fn run(urls: Vec<&str>) -> std::io::Result<BuildReport> {
+    run_with_options(urls, &RunOptions::default()).await
+}
+
+/// The settings every client built here shares.
+fn client_builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder().connect_timeout(std::time::Duration::from_secs(3))
+}
+
+/// The client used when the caller doesn't provide one.
+fn default_client() -> reqwest::Client {
+    client_builder()
+        .build()
+        .expect("Failed to build reqwest client")
+}
+
+/// The client deploying to routers with a self-signed certificate.
+fn insecure_client() -> reqwest::Client {
+    client_builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .expect("Failed to build reqwest client")
+}
+
+/// Fetches all sources in parallel, returning each source with its result
+/// and fetch time in the original order.
+async fn fetch_all<'a>(
+    client: &reqwest::Client,
+    sources: &'a [Source],
+    options: &RunOptions,
+    console: &Console,
+) -> Vec<(&'a Source, Result<FetchedSource>, std::time::Duration)> {
     // Fetch all sources in parallel using tokio::task::JoinSet (no extra crate needed)
     // Preserving original URL order via indexed results
+    let cache = options.cache_dir.clone().map(FetchCache::new);
     let mut join_set = tokio::task::JoinSet::new();
-    for (i, url) in urls.iter().enumerate() {
-        let url = url.to_string();
+    for (i, source) in sources.iter().enumerate() {
+        let url = source.url.clone();
+        // Clones share the connection pool (Keep-Alive)
         let client = client.clone();
+        let cache = cache.clone();
+        let console = console.clone();
+        let max_staleness = options.max_staleness;
         join_set.spawn(async move {
             let t = std::time::Instant::now();
-            let result = fetch_rules(&client, &url).await;
+            let result = fetch_source(&client, &url, cache.as_ref(), max_staleness, &console).await;
             let elapsed = t.elapsed();
-            (i, url, result, elapsed)
+            (i, result, elapsed)
         });
     }
 
     // Collect results indexed by original position, then sort to restore URL order
-    let mut indexed_results = Vec::with_capacity(urls.len());
+    let mut indexed_results = Vec::with_capacity(sources.len());
     while let Some(res) = join_set.join_next().await {
         let task_result = res.expect("task panicked");
         indexed_results.push(task_result);
     }
-    indexed_results.sort_unstable_by_key(|(i, _, _, _)| *i);
+    indexed_results.sort_unstable_by_key(|(i, _, _)| *i);
+    indexed_results
+        .into_iter()
+        .map(|(i, result, elapsed)| (&sources[i], result, elapsed))
+        .collect()
+}
 
-    for (_, url, result, fetch_elapsed) in indexed_results {
-        // Short filename (last URL path segment) — matches Python's
-        // url.split('/')[-1], used in progress output so long URLs don't
-        // clutter the console.
-        let short = url.split('/').next_back().unwrap_or(&url).to_string();
+/// Directory [`run_with_options`] writes to: `options.output_dir`, else the
+/// `OUTPUT_DIR` environment variable, else the working directory.
+fn output_dir(options: &RunOptions) -> PathBuf {
+    // OUTPUT_DIR is set in Docker to /output (a dedicated writable volume).
+    // When running locally (cargo run), OUTPUT_DIR is not set -> writes to CWD.
+    match (&options.output_dir, std::env::var("OUTPUT_DIR")) {
+        (Some(dir), _) => dir.clone(),
+        (None, Ok(dir)) => PathBuf::from(dir),
+        (None, Err(_)) => PathBuf::new(),
+    }
+}
 
-        match result {
-            Ok(rules) => {
-                println!(
-                    "Fetched {} lines from {} ({:.2}s)",
-                    format_with_commas(rules.len()),
-                    short,
-                    fetch_elapsed.as_secs_f64()
-                );
-                let mut converted: Vec<String> = Vec::new();
-                for rule in rules.iter() {
-                    if let Some(entry) = convert_rule(rule) {
-                        // Extract domain part by stripping the fixed prefix
-                        let domain = entry[ENTRY_PREFIX.len()..].to_string();
-                        if seen_domains.insert(domain) {
-                            converted.push(entry);
+/// Fetches every source, merges them and writes the output to
+/// [`RunOptions::output_dir`] (or `OUTPUT_DIR`, or the working directory),
+/// printing progress to the console. A [`Converter`] with a
+/// [`DirectorySink`] and a printing logger.
+///
+/// On failure the error is returned instead of the report, but a report
+/// requested with [`RunOptions::report`] is still written, with its `error`
+/// set.
+pub async fn run_with_options<I>(sources: I, options: &RunOptions) -> std::io::Result<BuildReport>
+where
+    I: IntoIterator,
+    I::Item: Into<Source>,
+{
+    Converter::new()
+        .sources(sources)
+        .options(options.clone())
+        .logger(print_logger(options.verbosity))
+        .run(&mut DirectorySink::new(output_dir(options)))
+        .await
+}
+
+/// Builder for a conversion, for embedding the converter in another program.
+///
+/// Unlike [`run_with_options`], a `Converter` reads no environment variables
+/// and prints nothing: the HTTP client, the sources, where the output goes
+/// ([`OutputSink`]) and where progress messages go ([`Converter::logger`])
+/// are all supplied by the caller.
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use adblock2mikrotik_rust::{Converter, MemorySink};
+///
+/// let converter = Converter::new()
+///     .source("https://example.com/list.txt")
+///     .logger(|level, message| eprintln!("{level:?}: {message}"));
+///
+/// // Domains in memory only
+/// let conversion = converter.convert().await;
+/// println!("{} domains", conversion.domains.len());
+///
+/// // Or the generated files, here kept in memory too
+/// let mut sink = MemorySink::default();
+/// converter.run(&mut sink).await?;
+/// let hosts = &sink.files["hosts.txt"];
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Converter {
+    sources: Vec<Source>,
+    options: RunOptions,
+    client: reqwest::Client,
+    /// Client for the routers with [`Router::accept_invalid_certs`], built
+    /// with the options when any router needs it.
+    insecure_client: Option<reqwest::Client>,
+    console: Console,
+}
+
+impl Default for Converter {
+    fn default() -> Self {
+        Converter {
+            sources: Vec::new(),
+            options: RunOptions::default(),
+            // Built once per Converter, so every convert() on the same
+            // Converter (or a clone) shares one connection pool
+            client: default_client(),
+            insecure_client: None,
+            console: Console::default(),
+        }
+    }
+}
+
+/// Result of [`Converter::convert`]: the merged domains, before anything is
+/// written.
+pub struct Conversion {
+    /// Every domain in the output.
+    pub domains: DomainSet,
+    /// Counts so far; [`Converter::write`] adds the files it wrote.
+    pub report: BuildReport,
+    sections: Vec<SourceOutput>,
+    /// Names of the hosts list files written, for deploying.
+    lists: Vec<String>,
+    started: std::time::Instant,
+}
+
+impl Conversion {
+    /// Domains grouped as in the output: one section per fetched source (by
+    /// URL, in source order), then the local denylist.
+    pub fn sections(&self) -> impl Iterator<Item = (&str, &[String])> {
+        self.sections
+            .iter()
+            .map(|section| (section.url.as_str(), section.domains.as_slice()))
+    }
+}
+
+impl Converter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds one source: a URL, or a [`Source`] with its own settings.
+    pub fn source(mut self, source: impl Into<Source>) -> Self {
+        self.sources.push(source.into());
+        self
+    }
+
+    /// Adds sources, in order.
+    pub fn sources<I>(mut self, sources: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Source>,
+    {
+        self.sources.extend(sources.into_iter().map(Into::into));
+        self
+    }
+
+    /// Sets the conversion options. `output_dir` and `verbosity` only apply
+    /// to [`run_with_options`]; a Converter writes to the sink it is given
+    /// and logs through [`Converter::logger`].
+    pub fn options(mut self, options: RunOptions) -> Self {
+        self.insecure_client = options
+            .routers
+            .iter()
+            .any(|router| router.accept_invalid_certs)
+            .then(insecure_client);
+        self.options = options;
+        self
+    }
+
+    /// Uses this client for every request, e.g. to share a connection pool
+    /// or set a proxy and timeouts. The default client has a 3s connect
+    /// timeout and nothing else.
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Receives every progress message and warning. Without a logger the
+    /// converter is silent.
+    pub fn logger(mut self, logger: impl Fn(LogLevel, &str) + Send + Sync + 'static) -> Self {
+        self.console = Console {
+            logger: Some(Arc::new(logger)),
+        };
+        self
+    }
+
+    /// Categories written to their own file with [`RunOptions::categories`],
+    /// in priority order: those listed in [`CategoryOptions::priority`], then
+    /// the others in the order their first source appears. Empty without
+    /// categories.
+    fn categories(&self) -> Vec<&str> {
+        let Some(options) = &self.options.categories else {
+            return Vec::new();
+        };
+        let present = self.sources.iter().filter_map(|s| s.category.as_deref());
+        let mut categories: Vec<&str> = Vec::new();
+        for category in options
+            .priority
+            .iter()
+            .map(String::as_str)
+            .filter(|c| present.clone().any(|p| p == *c))
+            .chain(present.clone())
+        {
+            if !categories.contains(&category) {
+                categories.push(category);
+            }
+        }
+        categories
+    }
+
+    /// Names of the hosts lists a build writes (before any split): the
+    /// combined hosts.txt and/or one file per category.
+    pub(crate) fn list_files(&self) -> Vec<String> {
+        let combined = self.options.categories.as_ref().is_none_or(|c| c.combined);
+        combined
+            .then(|| HOSTS_FILE.to_string())
+            .into_iter()
+            .chain(
+                self.categories()
+                    .into_iter()
+                    .map(output::category_file_name),
+            )
+            .collect()
+    }
+
+    /// Fetches and merges every source in memory: deduplication, exception
+    /// rules, the local allowlist and denylist, and subdomain pruning.
+    /// Sources that can't be fetched are skipped and reported in
+    /// [`BuildReport::sources`].
+    pub async fn convert(&self) -> Conversion {
+        let started = std::time::Instant::now();
+        let options = &self.options;
+        let console = &self.console;
+        let mut report = BuildReport {
+            started_at: Utc::now().to_rfc3339(),
+            dry_run: options.dry_run,
+            ..Default::default()
+        };
+        // DomainSet stores domain strings for uniqueness and parent-domain checks
+        // Pre-allocate for expected ~300k domains to avoid rehashing
+        let mut seen_domains = DomainSet::with_capacity(300_000);
+        let mut source_data: Vec<SourceOutput> = Vec::new();
+        // `@@||domain^` exception rules from all sources. Collected separately and
+        // applied once every source is merged, so an exception in one list also
+        // cancels a block coming from another.
+        let mut exceptions = DomainSet::new();
+
+        console.info(format_args!(
+            "Starting conversion of {} source(s)...\n",
+            self.sources.len()
+        ));
+
+        // A domain listed by several sources is kept by the one merged first.
+        // With categories, sources are merged in category priority order
+        // (uncategorized ones last), so the category with the highest
+        // priority owns it, then put back in source order.
+        let categories = self.categories();
+        let rank = |source: &Source| {
+            source
+                .category
+                .as_deref()
+                .and_then(|category| categories.iter().position(|c| *c == category))
+                .unwrap_or(categories.len())
+        };
+        let mut fetched: Vec<_> = fetch_all(&self.client, &self.sources, options, console)
+            .await
+            .into_iter()
+            .enumerate()
+            .collect();
+        fetched.sort_by_key(|(_, (source, ..))| rank(source));
+        let mut merged = Vec::with_capacity(fetched.len());
+
+        for (index, (source, result, fetch_elapsed)) in fetched {
+            let mut section = None;
+            let url = source.url.clone();
+            let short = source.short_name();
+            let mut source_report = SourceReport {
+                url: url.clone(),
+                name: source.name.clone(),
+                category: source.category.clone(),
+                fetch_secs: fetch_elapsed.as_secs_f64(),
+                ..Default::default()
+            };
+
+            match result {
+                Ok(FetchedSource { body, stale_age }) => {
+                    let rules = body_to_rules(&body);
+                    console.info(format_args!(
+                        "Fetched {} lines from {} ({:.2}s)",
+                        format_with_commas(rules.len()),
+                        short,
+                        fetch_elapsed.as_secs_f64()
+                    ));
+                    let format = match source.format {
+                        Some(format) => format,
+                        None => {
+                            let format = SourceFormat::detect(&rules);
+                            if format != SourceFormat::Adblock {
+                                console.info(format_args!("Detected {format} format for {short}"));
+                            }
+                            format
+                        }
+                    };
+                    let mut converted: Vec<String> = Vec::new();
+                    let (mut duplicates, mut skipped) = (0, 0);
+                    for rule in rules.iter() {
+                        let mut domains = format.parse_line(rule).peekable();
+                        if domains.peek().is_some() {
+                            for domain in domains {
+                                if seen_domains.insert(&domain) {
+                                    converted.push(domain.into_owned());
+                                } else {
+                                    duplicates += 1;
+                                }
+                            }
+                        } else if format == SourceFormat::Adblock
+                            && let Some(domain) = parse_exception(rule)
+                        {
+                            exceptions.insert(&domain);
+                        } else {
+                            skipped += 1;
                         }
                     }
+                    console.detail(format_args!(
+                        "Skipped {} duplicate domains and {} other lines (comments, unsupported rules) from {}",
+                        format_with_commas(duplicates),
+                        format_with_commas(skipped),
+                        short
+                    ));
+                    console.info(format_args!(
+                        "Converted {} unique domains from {}\n",
+                        format_with_commas(converted.len()),
+                        short
+                    ));
+                    source_report.format = Some(format);
+                    source_report.fetched_lines = rules.len();
+                    source_report.converted = converted.len();
+                    source_report.duplicates = duplicates;
+                    source_report.invalid = skipped;
+                    source_report.stale_age_secs = stale_age;
+                    section = Some(SourceOutput {
+                        url,
+                        name: source.name.clone(),
+                        category: source.category.clone(),
+                        domains: converted,
+                        stale_age,
+                        ..Default::default()
+                    });
+                }
+                Err(e) => {
+                    console.warn(format_args!("Failed to fetch rules from {url}: {e}"));
+                    source_report.error = Some(format!("{e:#}"));
                 }
-                println!(
-                    "Converted {} unique domains from {}\n",
-                    format_with_commas(converted.len()),
-                    short
-                );
-                source_data.push((url, converted));
             }
-            Err(e) => {
-                eprintln!("Failed to fetch rules from {url}: {e}");
+            merged.push((index, source_report, section));
+        }
+        merged.sort_by_key(|(index, ..)| *index);
+        for (_, source_report, section) in merged {
+            report.sources.push(source_report);
+            source_data.extend(section);
+        }
+        report.exception_rules = exceptions.len();
+
+        let allowlist_entries = collect_local_entries(&options.allowlist, "allowlist", console);
+        let allowlist: DomainSet = allowlist_entries.iter().map(String::as_str).collect();
+
+        // Subtract the local allowlist and exception rules (exact and
+        // subdomain-scoped) from the merged set, tracking per source how many
+        // entries each removed for the header. A domain matched by both is
+        // counted under the allowlist.
+        if !exceptions.is_empty() || !allowlist.is_empty() {
+            for source in source_data.iter_mut() {
+                let (mut by_allowlist, mut by_exceptions) = (0, 0);
+                source.domains.retain(|domain| {
+                    if allowlist.covers(domain) {
+                        by_allowlist += 1;
+                    } else if exceptions.covers(domain) {
+                        by_exceptions += 1;
+                    } else {
+                        return true;
+                    }
+                    seen_domains.remove(domain);
+                    false
+                });
+                source.removed_by_allowlist = by_allowlist;
+                source.removed_by_exceptions = by_exceptions;
+            }
+            if !exceptions.is_empty() {
+                console.info(format_args!(
+                    "Removed {} domains matching {} exception rules",
+                    format_with_commas(source_data.iter().map(|s| s.removed_by_exceptions).sum()),
+                    format_with_commas(exceptions.len())
+                ));
+            }
+            if !allowlist.is_empty() {
+                console.info(format_args!(
+                    "Removed {} domains matching {} local allowlist entries",
+                    format_with_commas(source_data.iter().map(|s| s.removed_by_allowlist).sum()),
+                    format_with_commas(allowlist.len())
+                ));
+            }
+            console.info("");
+        }
+
+        // Local denylist goes in its own section, after deduplication against
+        // the upstream sources. Entries already blocked upstream stay where they
+        // are; the local allowlist still wins over the denylist.
+        let mut denied: Vec<String> = Vec::new();
+        for domain in collect_local_entries(&options.denylist, "denylist", console) {
+            if !allowlist.covers(&domain) && seen_domains.insert(&domain) {
+                denied.push(domain);
+            }
+        }
+        report.denylisted = denied.len();
+        if !denied.is_empty() {
+            console.info(format_args!(
+                "Added {} domains from the local denylist\n",
+                format_with_commas(denied.len())
+            ));
+            source_data.push(SourceOutput {
+                url: LOCAL_DENYLIST.to_string(),
+                domains: denied,
+                ..Default::default()
+            });
+        }
+
+        // The allowlist only removes entries at or below each allowlisted
+        // domain; a blocked parent still blocks it on the router, which
+        // matches every subdomain of an adlist entry
+        for domain in &allowlist_entries {
+            if let Some(parent) = seen_domains.ancestor(domain) {
+                console.warn(format_args!(
+                    "allowlisted {domain} is still blocked through its parent domain {parent}"
+                ));
+            }
+        }
+
+        // Collapse redundant subdomains across all sources: a domain is dropped
+        // when any parent domain is still in the merged set, wherever it came
+        // from. Runs after exceptions and the allowlist so a parent they removed
+        // doesn't prune children that are still blocked. Removing a child never
+        // affects its ancestors' checks, so pruning in place is order-independent.
+        // With categories, each category file only counts a parent in the same
+        // category, so it still blocks everything its sources list: subdomains
+        // covered only from another category are kept aside for it.
+        let mut total_pruned = 0;
+        if options.prune_subdomains {
+            let by_category = options.categories.is_some().then(|| {
+                let mut groups: HashMap<Option<String>, DomainSet> = HashMap::new();
+                for source in &source_data {
+                    let group = groups.entry(source.category.clone()).or_default();
+                    for domain in &source.domains {
+                        group.insert(domain);
+                    }
+                }
+                groups
+            });
+            for source in source_data.iter_mut() {
+                let before = source.domains.len();
+                let mut category_only = Vec::new();
+                source.domains.retain(|domain| {
+                    if !seen_domains.has_ancestor(domain) {
+                        return true;
+                    }
+                    if let Some(groups) = &by_category
+                        && !groups[&source.category].has_ancestor(domain)
+                    {
+                        category_only.push(domain.clone());
+                    }
+                    seen_domains.remove(domain);
+                    false
+                });
+                source.category_only = category_only;
+                source.pruned_subdomains = before - source.domains.len();
+                total_pruned += source.pruned_subdomains;
+            }
+            if total_pruned > 0 {
+                console.info(format_args!(
+                    "Pruned {} redundant subdomains already covered by a blocked parent domain\n",
+                    format_with_commas(total_pruned)
+                ));
             }
         }
+
+        // Per-source counts after exceptions, allowlist and pruning. Fetched
+        // sources map one-to-one, in order, to the upstream sections.
+        let fetched = report.sources.iter_mut().filter(|s| s.error.is_none());
+        for (source_report, source) in fetched.zip(&source_data) {
+            source_report.removed_by_exceptions = source.removed_by_exceptions;
+            source_report.removed_by_allowlist = source.removed_by_allowlist;
+            source_report.pruned_subdomains = source.pruned_subdomains;
+            source_report.domains = source.domains.len();
+        }
+        report.pruned_subdomains = total_pruned;
+        report.total_unique = seen_domains.len();
+
+        Conversion {
+            domains: seen_domains,
+            report,
+            sections: source_data,
+            lists: Vec::new(),
+            started,
+        }
     }
 
-    if seen_domains.is_empty() {
-        eprintln!("Warning: No valid rules were converted. Skipping writing to file.");
-        return Ok(());
+    /// Renders the output files of a conversion and writes them to `sink`,
+    /// after the `max_change_percent` safety check against the files already
+    /// there. With `dry_run` set, stops after the check and writes nothing.
+    pub async fn write<S: OutputSink>(
+        &self,
+        conversion: &mut Conversion,
+        sink: &mut S,
+    ) -> std::io::Result<()> {
+        let options = &self.options;
+        let console = &self.console;
+        let source_data = &conversion.sections;
+        let failed_sources = conversion
+            .report
+            .sources
+            .iter()
+            .filter(|s| s.error.is_some())
+            .count();
+
+        // Sources and their reports map one-to-one, in order
+        if let Some(source) = self
+            .sources
+            .iter()
+            .zip(&conversion.report.sources)
+            .find_map(|(source, report)| {
+                (source.required && report.error.is_some()).then_some(source)
+            })
+        {
+            return Err(std::io::Error::other(format!(
+                "required source {} could not be fetched",
+                source.short_name()
+            )));
+        }
+
+        output::check_categories(self.categories())?;
+
+        if conversion.domains.is_empty() {
+            console.warn("No valid rules were converted. Skipping writing to file.");
+            if options.dry_run {
+                return Err(std::io::Error::other("no valid rules were converted"));
+            }
+            return Ok(());
+        }
+
+        let total_unique = conversion.domains.len();
+        let timestamp = Utc::now().format("%Y-%m-%d %H:%M:%S UTC").to_string();
+
+        // Build header with all stats and info at the top. Lists the
+        // original urls so failed sources still appear in the header.
+        let combined_header = options.header.render(&HeaderValues {
+            category: None,
+            timestamp: timestamp.clone(),
+            sources: self.sources.iter().map(|s| s.url.clone()).collect(),
+            total: total_unique,
+            pruned: conversion.report.pruned_subdomains,
+            source_counts: source_counts(source_data),
+        });
+
+        // Every hosts list to write: the combined one, then one per category
+        // holding the sections of its sources, with its own header stats.
+        let mut lists: Vec<HostsList> = Vec::new();
+        if options.categories.as_ref().is_none_or(|c| c.combined) {
+            lists.push(HostsList {
+                file: HOSTS_FILE.to_string(),
+                category: None,
+                header: combined_header.clone(),
+                sections: source_data.iter().collect(),
+                total: total_unique,
+            });
+        } else if source_data.iter().any(|s| s.category.is_none()) {
+            console.warn(
+                "sources without a category and the local denylist are only written to the combined hosts.txt, which is disabled",
+            );
+        }
+        let category_views: Vec<Option<SourceOutput>> = source_data
+            .iter()
+            .map(SourceOutput::category_view)
+            .collect();
+        for category in self.categories() {
+            let file = output::category_file_name(category);
+            let sections: Vec<&SourceOutput> = source_data
+                .iter()
+                .zip(&category_views)
+                .filter(|(s, _)| s.category.as_deref() == Some(category))
+                .map(|(s, view)| view.as_ref().unwrap_or(s))
+                .collect();
+            let total = sections.iter().map(|s| s.domains.len()).sum();
+            // Point the "URL to add in RouterOS" at this file
+            let mut template = options.header.clone();
+            if let Some(base) = template.url.strip_suffix(HOSTS_FILE) {
+                template.url = format!("{base}{file}");
+            }
+            let header = template.render(&HeaderValues {
+                category: Some(category.to_string()),
+                timestamp: timestamp.clone(),
+                sources: self
+                    .sources
+                    .iter()
+                    .filter(|s| s.category.as_deref() == Some(category))
+                    .map(|s| s.url.clone())
+                    .collect(),
+                total,
+                pruned: sections.iter().map(|s| s.pruned_subdomains).sum(),
+                source_counts: source_counts(sections.iter().copied()),
+            });
+            lists.push(HostsList {
+                file,
+                category: Some(category),
+                header,
+                sections,
+                total,
+            });
+        }
+
+        let split = options.max_bytes_per_file.is_some() || options.max_entries_per_file.is_some();
+        let mut written: Vec<PathBuf> = Vec::new();
+
+        // Safety threshold: refuse to replace the published list when the new
+        // total differs too much from the previous one, which usually means a
+        // truncated download or an upstream format change rather than a real
+        // change in the blocklists. Every list is checked before any is written.
+        if let Some(max_percent) = options.max_change_percent {
+            for list in &lists {
+                // The previous build may have used the other layout, when
+                // splitting was just turned on or off
+                let index_file = output::index_file_name(&list.file);
+                let layouts = if split {
+                    [&index_file, &list.file]
+                } else {
+                    [&list.file, &index_file]
+                };
+                let mut previous = None;
+                for file in layouts {
+                    if let Some(total) = sink
+                        .read(file)
+                        .await
+                        .and_then(|content| previous_total(&String::from_utf8_lossy(&content)))
+                    {
+                        previous = Some((file, total));
+                        break;
+                    }
+                }
+                let Some((previous_file, previous)) = previous else {
+                    continue;
+                };
+                let change = change_percent(previous, list.total);
+                if change > max_percent {
+                    let what = match list.category {
+                        Some(_) => format!("Domains in {}", list.file),
+                        None => "Total unique domains".to_string(),
+                    };
+                    let message = format!(
+                        "{what} changed by {change:.1}% ({} -> {}), more than the allowed {max_percent}%",
+                        format_with_commas(previous),
+                        format_with_commas(list.total)
+                    );
+                    if !options.force {
+                        if options.dry_run {
+                            return Err(std::io::Error::other(message));
+                        }
+                        return Err(std::io::Error::other(format!(
+                            "{message}. Keeping {previous_file}. Run with --force to write the new list anyway."
+                        )));
+                    }
+                    console.warn(format_args!("{message}. Writing anyway (--force)."));
+                }
+            }
+        }
+
+        if options.dry_run {
+            console.info(format_args!(
+                "Total unique domains across all sources: {}",
+                format_with_commas(total_unique)
+            ));
+            console.info(format_args!(
+                "Check passed, nothing written (dry run). Elapsed: {:.2}s",
+                conversion.started.elapsed().as_secs_f64()
+            ));
+            if failed_sources > 0 {
+                return Err(std::io::Error::other(format!(
+                    "{failed_sources} source(s) could not be fetched"
+                )));
+            }
+            return Ok(());
+        }
+
+        for list in &lists {
+            if split {
+                let parts = output::render_hosts_parts(
+                    &list.header,
+                    list.sections.iter().copied(),
+                    options.max_bytes_per_file,
+                    options.max_entries_per_file,
+                    &options.entry_format,
+                )?;
+                let mut index = Vec::with_capacity(parts.len());
+                for (i, part) in parts.iter().enumerate() {
+                    let name = output::part_file_name(&list.file, i + 1);
+                    self.write_file(sink, &name, part.content.as_bytes(), &mut written)
+                        .await?;
+                    conversion.lists.push(name.clone());
+                    index.push((name, part.domains));
+                }
+                // Index last, so it never lists a part that hasn't been written yet
+                let content = output::render_index(&list.header, &index, list.total);
+                let index_file = output::index_file_name(&list.file);
+                self.write_file(sink, &index_file, content.as_bytes(), &mut written)
+                    .await?;
+
+                // Remove parts left over from a previous build that needed more
+                // files, and the single file of a build that wasn't split
+                self.remove_parts(sink, &list.file, parts.len() + 1).await;
+                self.remove_file(sink, &list.file).await;
+            } else {
+                let content = output::render_list(
+                    &list.header,
+                    list.sections.iter().copied(),
+                    list.total,
+                    ListFormat::Hosts,
+                    &options.entry_format,
+                );
+                self.write_file(sink, &list.file, content.as_bytes(), &mut written)
+                    .await?;
+                conversion.lists.push(list.file.clone());
+
+                // Remove the parts and index of a previous build that was split
+                self.remove_file(sink, &output::index_file_name(&list.file))
+                    .await;
+                self.remove_parts(sink, &list.file, 1).await;
+            }
+        }
+
+        if let Some(rsc) = &options.rsc {
+            let script = output::render_rsc(
+                &combined_header,
+                source_data,
+                total_unique,
+                rsc,
+                &options.entry_format,
+            );
+            self.write_file(sink, &rsc.file_name, script.as_bytes(), &mut written)
+                .await?;
+        }
+
+        for output in &options.formats {
+            let content = output::render_list(
+                &combined_header,
+                source_data,
+                total_unique,
+                output.format,
+                &options.entry_format,
+            );
+            self.write_file(sink, &output.file_name, content.as_bytes(), &mut written)
+                .await?;
+        }
+
+        console.info(format_args!(
+            "Total unique domains across all sources: {}",
+            format_with_commas(total_unique)
+        ));
+        for list in lists.iter().filter(|list| list.category.is_some()) {
+            console.info(format_args!(
+                "{}: {} domains",
+                list.file,
+                format_with_commas(list.total)
+            ));
+        }
+        for path in &written {
+            console.info(format_args!("Done! Written to: {}", path.display()));
+        }
+        console.info(format_args!(
+            "Elapsed: {:.2}s",
+            conversion.started.elapsed().as_secs_f64()
+        ));
+        conversion.report.files = written;
+
+        Ok(())
     }
 
-    let total_unique = seen_domains.len();
+    /// Writes one generated file, then its compressed copies requested with
+    /// [`RunOptions::compression`] (e.g. hosts.txt.gz), each replaced
+    /// atomically by the sink. Copies in a compression no longer requested
+    /// are removed, so a server preferring them doesn't keep serving an old
+    /// list.
+    async fn write_file<S: OutputSink>(
+        &self,
+        sink: &mut S,
+        name: &str,
+        content: &[u8],
+        written: &mut Vec<PathBuf>,
+    ) -> std::io::Result<()> {
+        written.push(sink.write(name, content).await?);
+        for compression in Compression::ALL {
+            let file_name = compression.file_name(name);
+            if self.options.compression.contains(&compression) {
+                let compressed = compression.compress(content)?;
+                written.push(sink.write(&file_name, &compressed).await?);
+            } else {
+                sink.remove(&file_name).await;
+            }
+        }
+        Ok(())
+    }
 
-    // Build header with all stats and info at the top
-    let current_time = Utc::now().format("%Y-%m-%d %H:%M:%S UTC").to_string();
+    /// Removes a file left over from a previous build together with its
+    /// compressed copies, returning false if the file didn't exist.
+    async fn remove_file<S: OutputSink>(&self, sink: &mut S, name: &str) -> bool {
+        for compression in Compression::ALL {
+            sink.remove(&compression.file_name(name)).await;
+        }
+        sink.remove(name).await
+    }
 
-    // Prepare lines before header to list all source URLs and their unique domain counts
-    // Uses original urls list so failed sources still appear in the header
-    let url_lines: String = urls.iter().map(|url| format!("# - {url}\n")).collect();
+    /// Removes the parts of `file` numbered `first` and up, stopping at the
+    /// first one that doesn't exist.
+    async fn remove_parts<S: OutputSink>(&self, sink: &mut S, file: &str, first: usize) {
+        let mut part = first;
+        while self
+            .remove_file(sink, &output::part_file_name(file, part))
+            .await
+        {
+            part += 1;
+        }
+    }
 
-    let source_lines: String = source_data
-        .iter()
-        .map(|(url, rules)| {
-            let short = url.split('/').next_back().unwrap_or(url);
-            format!("# - {short} --> {:} unique domains\n", rules.len())
-        })
-        .collect();
+    /// [`convert`](Converter::convert), then [`finish`](Converter::finish).
+    pub async fn run<S: OutputSink>(&self, sink: &mut S) -> std::io::Result<BuildReport> {
+        let conversion = self.convert().await;
+        self.finish(conversion, sink).await
+    }
 
-    let header = format!(
-        "# Title: Unified DNS blocklist optimized for RouterOS, compiled from Hagezi sources\n\
-         #\n\
-         # URL to add in RouterOS:\n\
-         # https://raw.githubusercontent.com/eugenescodes/adblock2mikrotik_rust/refs/heads/main/hosts.txt\n\
-         #\n\
-         # Homepage: https://github.com/eugenescodes/adblock2mikrotik_rust\n\
-         # License: https://github.com/eugenescodes/adblock2mikrotik_rust/blob/main/LICENSE\n\
-         #\n\
-         # Last modified: {current_time}\n\
-         #\n\
-         # This filter is generated using the following Hagezi DNS blocklist sources:\n\
-         {url_lines}\
-         #\n\
-         # Total unique domains: {total_unique}\n\
-         {source_lines}\
-         #\n\
-         # Format: 0.0.0.0 domain.tld\n\
-         #\n"
-    );
+    /// [`write`](Converter::write) to `sink` and [`OutputSink::commit`],
+    /// then the deployment to [`RunOptions::routers`] and the report
+    /// requested with [`RunOptions::report`] (also written when the build
+    /// fails, with its `error` set). Routers are only told about a build once
+    /// it is committed, so one fetching the files from [`serve`] gets the new
+    /// ones. A failed deployment is logged and recorded in the report, but
+    /// doesn't fail the build: the files were written.
+    pub async fn finish<S: OutputSink>(
+        &self,
+        conversion: Conversion,
+        sink: &mut S,
+    ) -> std::io::Result<BuildReport> {
+        self.finish_until(conversion, sink, std::future::pending())
+            .await
+    }
 
-    // OUTPUT_DIR is set in Docker to /output (a dedicated writable volume).
-    // When running locally (cargo run), OUTPUT_DIR is not set -> writes to CWD.
-    let output_file: PathBuf = match std::env::var("OUTPUT_DIR") {
-        Ok(dir) => PathBuf::from(dir).join("hosts.txt"),
-        Err(_) => PathBuf::from("hosts.txt"),
-    };
+    /// [`finish`](Converter::finish), abandoning the deployment once
+    /// `shutdown` completes: its REST calls can take minutes, while writing
+    /// the files and the report is always run to the end.
+    pub(crate) async fn finish_until<S: OutputSink>(
+        &self,
+        mut conversion: Conversion,
+        sink: &mut S,
+        shutdown: impl Future<Output = ()>,
+    ) -> std::io::Result<BuildReport> {
+        let mut result = self.write(&mut conversion, sink).await;
+        if result.is_ok() {
+            result = sink.commit().await;
+        }
+        let mut report = conversion.report;
+        if result.is_ok() && !conversion.lists.is_empty() {
+            report.routers = deploy::deploy_all(
+                &self.client,
+                self.insecure_client.as_ref(),
+                &self.options.routers,
+                &conversion.lists,
+                &self.console,
+                shutdown,
+            )
+            .await;
+        }
+        report.elapsed_secs = conversion.started.elapsed().as_secs_f64();
+        if let Err(e) = &result {
+            report.error = Some(e.to_string());
+        }
 
-    // Pre-allocate content buffer: header + avg 35 bytes per domain entry
-    let estimated_capacity = header.len() + total_unique * 35;
-    let mut content = String::with_capacity(estimated_capacity);
-    content.push_str(&header);
-
-    for (url, rules) in &source_data {
-        content.push_str("\n# Source: ");
-        content.push_str(url);
-        content.push_str("\n\n");
-        for rule in rules {
-            content.push_str(rule);
-            content.push('\n'); // single char push — no format! allocation
-        }
-        content.push_str("\n# Converted ");
-        content.push_str(&format_with_commas(rules.len()));
-        content.push_str(" rules from this source\n\n");
-    }
-
-    content.push_str("\n# Total unique domains: ");
-    content.push_str(&total_unique.to_string());
-    content.push('\n');
-
-    // Write atomically: content is first written to a hidden temp file in the
-    // same directory as output_file, then moved into place with
-    // tokio::fs::rename() — an atomic rename on POSIX and Windows, same
-    // guarantee as Python's Path.replace() in the sibling project. This
-    // ensures readers of output_file (RouterOS polling it over HTTP, or a
-    // concurrent process) never observe a partially-written file, even if
-    // this process is interrupted mid-write. On failure, the temp file is
-    // removed and the error is returned; output_file is left untouched.
-    let tmp_file_name = format!(
-        ".{}.tmp",
-        output_file
-            .file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("hosts.txt")
-    );
-    let tmp_file = output_file.with_file_name(tmp_file_name);
-
-    if let Err(e) = tokio::fs::write(&tmp_file, &content).await {
-        eprintln!("Failed to write file: {e}");
-        let _ = tokio::fs::remove_file(&tmp_file).await;
-        return Err(e);
-    }
-
-    if let Err(e) = tokio::fs::rename(&tmp_file, &output_file).await {
-        eprintln!("Failed to move temp file into place: {e}");
-        let _ = tokio::fs::remove_file(&tmp_file).await;
-        return Err(e);
-    }
-    println!(
-        "Total unique domains across all sources: {}",
-        format_with_commas(total_unique)
-    );
-    println!("Done! Written to: {}", output_file.display());
-    println!("Elapsed: {:.2}s", start_time.elapsed().as_secs_f64());
-
-    Ok(())
+        let report_path = self.options.report_path.as_ref();
+        if let Some(format) = self.options.report
+            && (report_path.is_some() || !self.options.dry_run)
+        {
+            let written = match (format.render(&report), report_path) {
+                (Ok(content), Some(path)) => {
+                    tokio::fs::write(path, content).await.map(|()| path.clone())
+                }
+                (Ok(content), None) => {
+                    sink.write_private(format.file_name(), content.as_bytes())
+                        .await
+                }
+                (Err(e), _) => Err(e),
+            };
+            match written {
+                Ok(path) => report.files.push(path),
+                Err(e) => self.console.warn(format_args!(
+                    "failed to write {}: {e}",
+                    report_path
+                        .map_or(Path::new(format.file_name()), |path| path)
+                        .display()
+                )),
+            }
+        }
+        result.map(|()| report)
+    }
 }
 
 #[cfg(test)]
@@ -375,7 +2046,7 @@ mod tests {
         let mut converted: Vec<String> = Vec::new();
         for rule in &rules {
             if let Some(entry) = convert_rule(rule) {
-                let domain = entry[ENTRY_PREFIX.len()..].to_string();
+                let domain = entry[EntryFormat::default().prefix().len()..].to_string();
                 if seen_domains.insert(domain) {
                     converted.push(entry);
                 }
@@ -465,9 +2136,59 @@ mod tests {
 
     #[test]
     fn test_convert_rule_unicode_in_domain() {
-        // Unicode chars are not ASCII alphanumeric — must be rejected
-        assert_eq!(convert_rule("||café.com^"), None);
-        assert_eq!(convert_rule("||例え.jp^"), None);
+        // Unicode labels are converted to their xn-- form (IDNA), not dropped
+        assert_eq!(
+            convert_rule("||café.com^"),
+            Some("0.0.0.0 xn--caf-dma.com".to_string())
+        );
+        assert_eq!(
+            convert_rule("||例え.jp^"),
+            Some("0.0.0.0 xn--r8jz45g.jp".to_string())
+        );
+        // Case-folded as part of the conversion
+        assert_eq!(
+            convert_rule("||CAFÉ.com^"),
+            Some("0.0.0.0 xn--caf-dma.com".to_string())
+        );
+        // Still validated after the conversion
+        assert_eq!(convert_rule("||café_bar.com^"), None);
+        assert_eq!(convert_rule("||café^"), None);
+    }
+
+    #[test]
+    fn test_idn_in_every_format() {
+        // Cyrillic "а" homograph of apple.com, a typical phishing entry
+        let homograph = "xn--pple-43d.com";
+        assert_eq!(
+            parse_hosts_line("0.0.0.0 аpple.com").collect::<Vec<_>>(),
+            [homograph]
+        );
+        assert_eq!(parse_domain_line("аpple.com").as_deref(), Some(homograph));
+        assert_eq!(
+            parse_exception("@@||аpple.com^").as_deref(),
+            Some(homograph)
+        );
+        assert_eq!(parse_local_entry("аpple.com").as_deref(), Some(homograph));
+        // ASCII input is borrowed, not reallocated
+        assert!(matches!(
+            parse_domain_line("example.com"),
+            Some(Cow::Borrowed("example.com"))
+        ));
+    }
+
+    #[test]
+    fn test_punycode_tld() {
+        // пример.рф, already encoded
+        assert!(is_valid_domain("xn--e1afmkfd.xn--p1ai"));
+        assert_eq!(
+            convert_rule("||пример.рф^"),
+            Some("0.0.0.0 xn--e1afmkfd.xn--p1ai".to_string())
+        );
+        assert!(is_valid_domain("example.XN--P1AI"));
+        // Other digit-bearing or malformed TLDs are still rejected
+        assert!(!is_valid_domain("example.c0m"));
+        assert!(!is_valid_domain("example.xn--"));
+        assert!(!is_valid_domain("example.xn--p1ai-"));
     }
 
     #[test]
@@ -475,4 +2196,155 @@ mod tests {
         assert_eq!(convert_rule("||-example.com^"), None);
         assert_eq!(convert_rule("||example-.com^"), None);
     }
+
+    #[test]
+    fn test_parse_hosts_line_skips_boilerplate() {
+        let domains = |line| parse_hosts_line(line).collect::<Vec<_>>();
+        assert!(domains("127.0.0.1 localhost").is_empty());
+        assert!(domains("127.0.0.1 localhost.localdomain").is_empty());
+        assert!(domains("0.0.0.0 0.0.0.0").is_empty());
+        assert!(domains("0.0.0.0").is_empty());
+        assert!(domains("999.0.0.1 example.com").is_empty());
+    }
+
+    #[test]
+    fn test_parse_hosts_line_every_hostname() {
+        let domains = |line| parse_hosts_line(line).collect::<Vec<_>>();
+        assert_eq!(domains("0.0.0.0 a.com b.com"), ["a.com", "b.com"]);
+        // Boilerplate and invalid names are skipped, not the whole line
+        assert_eq!(
+            domains("127.0.0.1 localhost ads.example.com bad_name # comment.com"),
+            ["ads.example.com"]
+        );
+    }
+
+    #[test]
+    fn test_detect_format_ignores_header_noise() {
+        let hosts: Vec<String> = [
+            "127.0.0.1 localhost",
+            "::1 localhost",
+            "0.0.0.0 ads.example.com",
+            "0.0.0.0 tracker.example.net",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        assert_eq!(SourceFormat::detect(&hosts), SourceFormat::Hosts);
+
+        let adblock: Vec<String> = ["[Adblock Plus]", "! Title: list", "||example.com^"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(SourceFormat::detect(&adblock), SourceFormat::Adblock);
+
+        assert_eq!(SourceFormat::detect(&[]), SourceFormat::Adblock);
+    }
+
+    #[test]
+    fn test_local_source_parse() {
+        assert_eq!(LocalSource::parse("-"), Some(LocalSource::Stdin));
+        assert_eq!(
+            LocalSource::parse("lists/inhouse.txt"),
+            Some(LocalSource::File(PathBuf::from("lists/inhouse.txt")))
+        );
+        assert_eq!(
+            LocalSource::parse("file:///srv/my%20list.txt"),
+            Some(LocalSource::File(PathBuf::from("/srv/my list.txt")))
+        );
+        assert_eq!(
+            LocalSource::parse("file://relative/list.txt"),
+            Some(LocalSource::File(PathBuf::from("relative/list.txt")))
+        );
+        assert_eq!(LocalSource::parse("https://example.com/list.txt"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fetch_source_respects_max_staleness() {
+        // Port 9 (discard) is closed: every attempt fails fast, and paused
+        // time skips the retry backoff.
+        let url = "http://127.0.0.1:9/list.txt";
+        let client = reqwest::Client::new();
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FetchCache::new(dir.path());
+        let ten_days = 10 * 86_400;
+        let meta = CacheMeta {
+            url: url.to_string(),
+            etag: None,
+            last_modified: None,
+            fetched_at: Utc::now().timestamp() - ten_days as i64,
+        };
+        cache.store(&meta, b"||example.com^\n").await.unwrap();
+
+        let week = std::time::Duration::from_secs(7 * 86_400);
+        assert!(
+            fetch_source(&client, url, Some(&cache), week, &Console::default())
+                .await
+                .is_err()
+        );
+
+        let month = std::time::Duration::from_secs(30 * 86_400);
+        let fetched = fetch_source(&client, url, Some(&cache), month, &Console::default())
+            .await
+            .expect("falls back to the cached copy");
+        assert_eq!(body_to_rules(&fetched.body), vec!["||example.com^"]);
+        assert!(fetched.stale_age.is_some_and(|age| age >= ten_days));
+
+        assert!(
+            fetch_source(&client, url, None, month, &Console::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_change_percent() {
+        assert_eq!(change_percent(100, 100), 0.0);
+        assert_eq!(change_percent(100, 150), 50.0);
+        assert!((change_percent(300_000, 2_000) - 99.33).abs() < 0.01);
+        assert_eq!(change_percent(0, 0), 0.0);
+        assert!(change_percent(0, 10).is_infinite());
+    }
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(0), "0m");
+        assert_eq!(format_age(7 * 60 + 5), "7m");
+        assert_eq!(format_age(5 * 3_600 + 12 * 60), "5h 12m");
+        assert_eq!(format_age(2 * 86_400 + 3 * 3_600 + 59), "2d 3h");
+    }
+
+    #[test]
+    fn test_convert_rule_ignores_exception() {
+        assert_eq!(convert_rule("@@||example.com^"), None);
+    }
+
+    #[test]
+    fn test_parse_exception() {
+        assert_eq!(
+            parse_exception("@@||example.com^").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            parse_exception("  @@||sub.example.com^$important  ").as_deref(),
+            Some("sub.example.com")
+        );
+        assert_eq!(parse_exception("@@|example.com^"), None);
+        assert_eq!(parse_exception("@@||invalid_domain^"), None);
+    }
+
+    #[test]
+    fn test_parse_exception_modifiers() {
+        assert_eq!(
+            parse_exception("@@||example.com^$document,important").as_deref(),
+            Some("example.com")
+        );
+        // Exceptions a DNS blocklist can't express are skipped
+        assert_eq!(parse_exception("@@||example.com^$domain=example.org"), None);
+        assert_eq!(parse_exception("@@||example.com^$badfilter"), None);
+        assert_eq!(
+            parse_exception("@@||example.com^$important,third-party"),
+            None
+        );
+        assert_eq!(parse_exception("@@||example.com^$"), None);
+    }
 }
@@ -0,0 +1,256 @@
+//! Explaining why a domain is (or isn't) blocked.
+
+use crate::{
+    Converter, DomainSet, FetchedSource, LOCAL_DENYLIST, RunOptions, Source, SourceFormat,
+    collect_local_entries, domain_set::parents, fetch_all, parse_exception, parse_local_entry,
+    print_logger,
+};
+use encoding_rs::UTF_8;
+use std::collections::HashMap;
+use std::fmt;
+
+/// What a matching line does to the looked-up domain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MatchKind {
+    /// Blocks the domain, either directly or through a parent domain.
+    /// `overridden_by` names the allowlist entry or exception rule that
+    /// removes it from the output, if any.
+    Block { overridden_by: Option<Override> },
+    /// An `@@||domain^` exception covering the domain.
+    Exception,
+}
+
+/// What removes a blocking entry from the output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Override {
+    /// A local allowlist entry covering the blocked domain.
+    Allowlist(String),
+    /// An exception rule (from any adblock source) covering the blocked domain.
+    Exception(String),
+}
+
+/// One line that mentions the looked-up domain or one of its parents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LookupMatch {
+    /// Source URL, or "local denylist".
+    pub source: String,
+    /// 1-based line number in the downloaded list; None for local entries.
+    pub line_number: Option<usize>,
+    /// The line as it appears in the list, trimmed.
+    pub line: String,
+    /// Domain the line targets: the looked-up domain or a parent of it.
+    pub domain: String,
+    pub kind: MatchKind,
+}
+
+/// Result of [`Converter::lookup`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LookupReport {
+    pub domain: String,
+    pub matches: Vec<LookupMatch>,
+    /// Sources that could not be fetched (nor served from the cache), so
+    /// the report may be incomplete.
+    pub failed_sources: Vec<String>,
+}
+
+impl LookupReport {
+    /// True if at least one blocking entry survives allowlist and exceptions,
+    /// i.e. the built list blocks the domain.
+    pub fn is_blocked(&self) -> bool {
+        self.matches.iter().any(|m| {
+            matches!(
+                m.kind,
+                MatchKind::Block {
+                    overridden_by: None
+                }
+            )
+        })
+    }
+}
+
+/// [`Converter::lookup`] with the given sources and options, printing
+/// progress to stdout (warnings to stderr) like [`crate::run_with_options`].
+pub async fn lookup<I>(
+    sources: I,
+    domain: &str,
+    options: &RunOptions,
+) -> std::io::Result<LookupReport>
+where
+    I: IntoIterator,
+    I::Item: Into<Source>,
+{
+    Converter::new()
+        .sources(sources)
+        .options(options.clone())
+        .logger(print_logger(options.verbosity))
+        .lookup(domain)
+        .await
+}
+
+impl Converter {
+    /// Fetches the sources through the same pipeline as
+    /// [`convert`](Converter::convert) (cache, stale fallback and format
+    /// detection included) and reports every line that blocks `domain` or
+    /// one of its parent domains, every exception rule covering it, and the
+    /// local denylist and allowlist entries involved.
+    ///
+    /// Fails only if `domain` is not a valid domain name; unreachable
+    /// sources are listed in [`LookupReport::failed_sources`].
+    pub async fn lookup(&self, domain: &str) -> std::io::Result<LookupReport> {
+        let input = domain.trim().trim_end_matches('.');
+        // Unicode input is looked up by its xn-- form, as the lists are converted
+        let Some(domain) = parse_local_entry(input).map(|domain| domain.into_owned()) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("not a valid domain: {input}"),
+            ));
+        };
+        let (options, console) = (&self.options, &self.console);
+        let mut report = LookupReport {
+            domain: domain.clone(),
+            ..Default::default()
+        };
+        // A line blocking any of these blocks the domain
+        let targets: DomainSet = std::iter::once(domain.as_str())
+            .chain(parents(&domain))
+            .collect();
+        // Exceptions from every source apply to every source, as in the
+        // build; each is reported with the first rule adding it
+        let mut exception_rules: HashMap<String, String> = HashMap::new();
+
+        for (source, result, _) in fetch_all(&self.client, &self.sources, options, console).await {
+            let url = &source.url;
+            let body = match result {
+                Ok(FetchedSource { body, .. }) => body,
+                Err(e) => {
+                    console.warn(format_args!("Failed to fetch rules from {url}: {e}"));
+                    report.failed_sources.push(url.clone());
+                    continue;
+                }
+            };
+            let (decoded, _) = UTF_8.decode_with_bom_removal(&body);
+            // Numbered as in the list, skipping empty and comment lines
+            let lines: Vec<(usize, &str)> = decoded
+                .lines()
+                .enumerate()
+                .map(|(i, raw)| (i + 1, raw.trim()))
+                .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+                .collect();
+            let format = source
+                .format
+                .unwrap_or_else(|| SourceFormat::detect_lines(lines.iter().map(|&(_, line)| line)));
+            for &(line_number, line) in &lines {
+                // A hosts line can block several domains
+                let mut matched: Vec<_> = format
+                    .parse_line(line)
+                    .map(|blocked| {
+                        let kind = MatchKind::Block {
+                            overridden_by: None,
+                        };
+                        (blocked, kind)
+                    })
+                    .collect();
+                if matched.is_empty()
+                    && format == SourceFormat::Adblock
+                    && let Some(exception) = parse_exception(line)
+                {
+                    exception_rules
+                        .entry(exception.to_string())
+                        .or_insert_with(|| line.to_string());
+                    matched.push((exception, MatchKind::Exception));
+                }
+                for (matched, kind) in matched {
+                    if targets.contains(&matched) {
+                        report.matches.push(LookupMatch {
+                            source: url.clone(),
+                            line_number: Some(line_number),
+                            line: line.to_string(),
+                            domain: matched.into_owned(),
+                            kind,
+                        });
+                    }
+                }
+            }
+        }
+
+        for entry in collect_local_entries(&options.denylist, "denylist", console) {
+            if targets.contains(&entry) {
+                report.matches.push(LookupMatch {
+                    source: LOCAL_DENYLIST.to_string(),
+                    line_number: None,
+                    line: entry.clone(),
+                    domain: entry,
+                    kind: MatchKind::Block {
+                        overridden_by: None,
+                    },
+                });
+            }
+        }
+
+        // Same precedence as the build: the allowlist wins over exceptions
+        let exceptions: DomainSet = exception_rules.keys().map(String::as_str).collect();
+        let allowlist: DomainSet = collect_local_entries(&options.allowlist, "allowlist", console)
+            .iter()
+            .map(String::as_str)
+            .collect();
+        for m in report.matches.iter_mut() {
+            if let MatchKind::Block { overridden_by } = &mut m.kind {
+                *overridden_by = if let Some(entry) = allowlist.covering(&m.domain) {
+                    Some(Override::Allowlist(entry.to_string()))
+                } else if m.source == LOCAL_DENYLIST {
+                    None
+                } else {
+                    exceptions
+                        .covering(&m.domain)
+                        .map(|exception| Override::Exception(exception_rules[exception].clone()))
+                };
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl fmt::Display for LookupReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verdict = if self.is_blocked() {
+            "BLOCKED"
+        } else {
+            "not blocked"
+        };
+        writeln!(f, "{}: {verdict}", self.domain)?;
+        if self.matches.is_empty() {
+            writeln!(f, "No source has a rule for it or any parent domain.")?;
+        }
+        for m in &self.matches {
+            let location = match m.line_number {
+                Some(n) => format!("{}:{n}", m.source),
+                None => m.source.clone(),
+            };
+            let target = if m.domain == self.domain {
+                String::new()
+            } else {
+                format!(" (parent domain {})", m.domain)
+            };
+            let note = match &m.kind {
+                MatchKind::Block {
+                    overridden_by: None,
+                } => "blocks".to_string(),
+                MatchKind::Block {
+                    overridden_by: Some(Override::Allowlist(entry)),
+                } => format!("overridden by allowlist entry {entry}"),
+                MatchKind::Block {
+                    overridden_by: Some(Override::Exception(rule)),
+                } => format!("overridden by exception {rule}"),
+                MatchKind::Exception => "exception".to_string(),
+            };
+            writeln!(f, "  {location}: {}{target} -- {note}", m.line)?;
+        }
+        for url in &self.failed_sources {
+            writeln!(
+                f,
+                "Warning: {url} could not be fetched, results may be incomplete"
+            )?;
+        }
+        Ok(())
+    }
+}
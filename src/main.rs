@@ -1,20 +1,255 @@
-use adblock2mikrotik_rust::run;
+mod cli;
+
+use adblock2mikrotik_rust::{
+    CategoryOptions, Compression, DeployAction, EntryFormat, FormatOutput, HeaderTemplate,
+    ListFormat, Router, RscMode, RscOptions, RunOptions, Schedule, Source, SourceFormat, Verbosity,
+    check_categories, lookup, run_daemon_with_options, run_with_options, serve_with_options,
+};
+use cli::Command;
 use serde::Deserialize;
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::{Deserializer, MapAccess, SeqAccess, Visitor};
 use std::env;
+use std::fmt;
 use std::io;
-use std::path::Path;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
+/// Keys no table knows, such as a misspelled `max_change_precent`, are
+/// collected by read_config: a warning for a build, an error for `check`.
 #[derive(Deserialize)]
 struct Config {
     sources: Option<Sources>,
+    allowlist: Option<DomainList>,
+    denylist: Option<DomainList>,
+    output: Option<Output>,
+    cache: Option<Cache>,
+    daemon: Option<Daemon>,
+    serve: Option<Serve>,
+    routers: Option<Vec<RouterConfig>>,
+}
+
+/// `[sources]` table with a plain `urls` list (the original form), or
+/// `[[sources]]` tables with per-source settings.
+#[derive(Clone)]
+enum Sources {
+    Urls { urls: Option<Vec<String>> },
+    Tables(Vec<SourceTable>),
 }
 
+/// `[sources]` table, the original form.
 #[derive(Deserialize)]
-struct Sources {
+struct UrlsTable {
     urls: Option<Vec<String>>,
 }
 
-const CONFIG_PATH: &str = "config.toml";
+/// Picks the form by whether `sources` is a table or an array, instead of
+/// `#[serde(untagged)]` trying both: untagged replaces the actual error, such
+/// as a misspelled `format` in one entry, with "data did not match any
+/// variant".
+impl<'de> Deserialize<'de> for Sources {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SourcesVisitor;
+
+        impl<'de> Visitor<'de> for SourcesVisitor {
+            type Value = Sources;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a [sources] table or [[sources]] tables")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Sources, A::Error> {
+                let table = UrlsTable::deserialize(MapAccessDeserializer::new(map))?;
+                Ok(Sources::Urls { urls: table.urls })
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Sources, A::Error> {
+                Vec::deserialize(SeqAccessDeserializer::new(seq)).map(Sources::Tables)
+            }
+        }
+
+        deserializer.deserialize_any(SourcesVisitor)
+    }
+}
+
+/// `[[sources]]` entry.
+#[derive(Clone, Deserialize)]
+struct SourceTable {
+    url: Option<String>,
+    name: Option<String>,
+    /// false keeps the entry in the config without fetching it.
+    enabled: Option<bool>,
+    /// "adblock", "hosts" or "domains"; detected when left out.
+    format: Option<SourceFormat>,
+    category: Option<String>,
+    required: Option<bool>,
+}
+
+impl Sources {
+    /// The enabled sources, or None for a `[sources]` table without `urls`.
+    /// A `[[sources]]` entry without a url is reported and skipped.
+    fn into_sources(self) -> Option<Vec<Source>> {
+        match self {
+            Sources::Urls { urls } => Some(urls?.into_iter().map(Source::from).collect()),
+            Sources::Tables(tables) => Some(
+                tables
+                    .into_iter()
+                    .filter(|table| table.enabled.unwrap_or(true))
+                    .filter_map(|table| {
+                        let Some(url) = table.url else {
+                            eprintln!("Warning: ignoring [[sources]] entry without a url");
+                            return None;
+                        };
+                        Some(Source {
+                            url,
+                            name: table.name,
+                            format: table.format,
+                            category: table.category,
+                            required: table.required.unwrap_or(false),
+                        })
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// `[output]` table: settings for the generated file.
+#[derive(Clone, Deserialize)]
+struct Output {
+    prune_subdomains: Option<bool>,
+    max_bytes_per_file: Option<usize>,
+    max_entries_per_file: Option<usize>,
+    /// "ipv4", "ipv6", "none" or an IP address; see EntryFormat.
+    sinkhole: Option<String>,
+    max_change_percent: Option<f64>,
+    /// "gzip" and/or "zstd" copies of every generated file.
+    compress: Option<Vec<Compression>>,
+    rsc: Option<Rsc>,
+    formats: Option<Vec<FormatTable>>,
+    categories: Option<Categories>,
+    header: Option<Header>,
+}
+
+/// `[output.header]` table: branding and layout of the file header. Any
+/// field left out keeps the built-in default.
+#[derive(Clone, Deserialize)]
+struct Header {
+    title: Option<String>,
+    url: Option<String>,
+    homepage: Option<String>,
+    license: Option<String>,
+    sources_heading: Option<String>,
+    /// Inline template with `{placeholder}`s; see HeaderTemplate.
+    template: Option<String>,
+    /// File holding the template, relative to the config file. Ignored when
+    /// `template` is set.
+    template_file: Option<String>,
+}
+
+/// `[output.rsc]` table: its presence enables the RouterOS script output.
+#[derive(Clone, Deserialize)]
+struct Rsc {
+    file: Option<String>,
+    mode: Option<RscMode>,
+    match_subdomain: Option<bool>,
+    comment: Option<String>,
+}
+
+impl From<Rsc> for RscOptions {
+    fn from(rsc: Rsc) -> Self {
+        let defaults = RscOptions::default();
+        RscOptions {
+            file_name: rsc.file.unwrap_or(defaults.file_name),
+            mode: rsc.mode.unwrap_or(defaults.mode),
+            match_subdomain: rsc.match_subdomain.unwrap_or(defaults.match_subdomain),
+            comment: rsc.comment.unwrap_or(defaults.comment),
+        }
+    }
+}
+
+/// `[[output.formats]]` entry: an extra list file in another resolver's
+/// format.
+#[derive(Clone, Deserialize)]
+struct FormatTable {
+    format: ListFormat,
+    /// Defaults to ListFormat::default_file_name.
+    file: Option<String>,
+}
+
+impl From<FormatTable> for FormatOutput {
+    fn from(table: FormatTable) -> Self {
+        FormatOutput {
+            file_name: table
+                .file
+                .unwrap_or_else(|| table.format.default_file_name().to_string()),
+            format: table.format,
+        }
+    }
+}
+
+/// `[output.categories]` table: its presence enables one hosts file per
+/// source category.
+#[derive(Clone, Deserialize)]
+struct Categories {
+    combined: Option<bool>,
+    priority: Option<Vec<String>>,
+}
+
+impl From<Categories> for CategoryOptions {
+    fn from(categories: Categories) -> Self {
+        let defaults = CategoryOptions::default();
+        CategoryOptions {
+            combined: categories.combined.unwrap_or(defaults.combined),
+            priority: categories.priority.unwrap_or(defaults.priority),
+        }
+    }
+}
+
+/// `[cache]` table: conditional-fetch cache settings.
+#[derive(Deserialize)]
+struct Cache {
+    dir: Option<String>,
+    max_staleness_hours: Option<u64>,
+}
+
+/// `[daemon]` table: rebuild schedule of the `daemon` command.
+#[derive(Deserialize)]
+struct Daemon {
+    interval_minutes: Option<u64>,
+    jitter_minutes: Option<u64>,
+}
+
+/// `[serve]` table: settings of the `serve` command's HTTP server.
+#[derive(Deserialize)]
+struct Serve {
+    listen: Option<String>,
+}
+
+/// `[[routers]]` entry: a router to deploy to after each build.
+#[derive(Clone, Deserialize)]
+struct RouterConfig {
+    url: Option<String>,
+    user: Option<String>,
+    password: Option<String>,
+    /// Environment variable holding the password, to keep it out of the
+    /// config file. Takes precedence over `password`.
+    password_env: Option<String>,
+    action: Option<DeployAction>,
+    /// Where the router downloads the files from for `action = "upload"`.
+    files_url: Option<String>,
+    accept_invalid_certs: Option<bool>,
+}
+
+/// `[allowlist]` / `[denylist]` table: inline domains plus files holding one
+/// domain per line.
+#[derive(Clone, Deserialize)]
+struct DomainList {
+    domains: Option<Vec<String>>,
+    files: Option<Vec<String>>,
+}
 
 /// config.toml.example embedded at compile time — the single source of
 /// truth for default sources. No runtime file dependency: unlike a
@@ -23,91 +258,560 @@ const CONFIG_PATH: &str = "config.toml";
 /// image.
 const DEFAULT_CONFIG_TOML: &str = include_str!("../config.toml.example");
 
-/// Default sources used as fallback if config.toml is not found, invalid,
-/// or has no [sources] table. Parsed from the bundled config.toml.example
+/// Default sources used as fallback if config.toml is not found or
+/// has no [sources] table. Parsed from the bundled config.toml.example
 /// (see DEFAULT_CONFIG_TOML) rather than duplicated as a separate literal,
 /// so the two never drift apart.
-fn default_sources() -> Vec<String> {
+fn default_sources() -> Vec<Source> {
     toml::from_str::<Config>(DEFAULT_CONFIG_TOML)
         .ok()
         .and_then(|config| config.sources)
-        .and_then(|sources| sources.urls)
+        .and_then(Sources::into_sources)
         .unwrap_or_default()
 }
 
-/// Load sources from a TOML config file at the given path.
+/// Set by `--quiet`: suppresses the config loading messages below, the same
+/// way the library's Verbosity suppresses its progress output.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Prints a progress message unless running with `--quiet`.
+fn info(message: fmt::Arguments) {
+    if !QUIET.load(Ordering::Relaxed) {
+        println!("{message}");
+    }
+}
+
+/// Load sources from the config file parsed from the given path (None if
+/// it doesn't exist).
 ///
 /// Takes the path explicitly (rather than reading a hardcoded constant
 /// internally) so tests can point it at an isolated temp file instead of
 /// mutating a real config.toml in the project's working directory.
 ///
 /// Console output and fallback structure:
-/// config.toml missing, unreadable, invalid TOML, or with no [sources]
-/// urls key all fall back to the embedded config.toml.example defaults,
-/// with the same "Note: ... using default sources" / "Loaded N default
-/// sources" messaging. An explicit `urls = []` is treated as an intentional
-/// override (convert nothing), not a missing value, and is returned as-is.
-fn load_config(config_path: &Path) -> Vec<String> {
-    let urls: Option<Vec<String>> = std::fs::read_to_string(config_path)
-        .ok()
-        .and_then(|content| toml::from_str::<Config>(&content).ok())
-        .and_then(|config| config.sources)
-        .and_then(|sources| sources.urls);
+/// config.toml missing, or with no [sources] urls key, falls back to the
+/// embedded config.toml.example defaults, with the same "Note: ... using
+/// default sources" / "Loaded N default sources" messaging. An explicit
+/// `urls = []` is treated as an intentional override (convert nothing), not
+/// a missing value, and is returned as-is; so are `[[sources]]` tables that
+/// are all disabled. A config.toml that can't be read or parsed is handled
+/// by read_config, before this runs.
+fn load_config(config: Option<&Config>, config_path: &Path) -> Vec<Source> {
+    let urls: Option<Vec<Source>> = config
+        .and_then(|config| config.sources.clone())
+        .and_then(Sources::into_sources);
 
     if let Some(urls) = urls {
-        println!(
+        info(format_args!(
             "Loaded {} sources from {}",
             urls.len(),
             config_path.display()
-        );
-        return urls;
+        ));
+        let base_dir = config_path.parent().unwrap_or(Path::new(""));
+        return urls
+            .into_iter()
+            .map(|source| resolve_source_path(source, base_dir))
+            .collect();
     }
 
     if config_path.exists() {
-        println!(
+        info(format_args!(
             "Note: {} has no usable [sources] urls, using default sources from config.toml.example",
             config_path.display()
-        );
+        ));
     } else {
-        println!(
+        info(format_args!(
             "Note: {} not found, using default sources from config.toml.example",
             config_path.display()
-        );
+        ));
     }
 
     let default_urls = default_sources();
     if default_urls.is_empty() {
         eprintln!("Error: config.toml.example is missing or has no [sources] urls.");
     } else {
-        println!(
+        info(format_args!(
             "Loaded {} default sources from config.toml.example",
             default_urls.len()
-        );
+        ));
     }
     default_urls
 }
 
+/// Read and parse the TOML config file, or None if it does not exist.
+///
+/// With `strict` (the `check` command), a file that can't be read or parsed,
+/// or that has keys no table knows, is an error carrying the toml message.
+/// Otherwise it is reported as a warning: an unparseable file is ignored as
+/// if it didn't exist, so the build falls back to the default sources, and
+/// unknown keys are skipped.
+fn read_config(config_path: &Path, strict: bool) -> io::Result<Option<Config>> {
+    let content = match std::fs::read_to_string(config_path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            let e = io::Error::new(
+                e.kind(),
+                format!("failed to read {}: {e}", config_path.display()),
+            );
+            return ignore_config(e, strict);
+        }
+    };
+    let mut unknown = Vec::new();
+    let parsed = toml::Deserializer::parse(&content)
+        .and_then(|de| serde_ignored::deserialize(de, |path| unknown.push(path.to_string())));
+    let config: Config = match parsed {
+        Ok(config) => config,
+        Err(e) => return ignore_config(invalid_config(config_path, e), strict),
+    };
+    if strict && !unknown.is_empty() {
+        return Err(invalid_config(
+            config_path,
+            format_args!("unknown key(s) {}", unknown.join(", ")),
+        ));
+    }
+    for key in unknown {
+        eprintln!(
+            "Warning: ignoring unknown key {key} in {}",
+            config_path.display()
+        );
+    }
+    Ok(Some(config))
+}
+
+/// A config.toml that can't be read or parsed: an error with `strict`,
+/// otherwise a warning, and the file is ignored.
+fn ignore_config(e: io::Error, strict: bool) -> io::Result<Option<Config>> {
+    if strict {
+        return Err(e);
+    }
+    eprintln!("Warning: {e}\nIgnoring it and using the defaults; run `check` to validate it");
+    Ok(None)
+}
+
+/// An InvalidData error for a setting in config.toml that parsed as TOML but
+/// can't be used, reported like a parse error.
+fn invalid_config(config_path: &Path, detail: impl fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid {}: {detail}", config_path.display()),
+    )
+}
+
+/// Resolve a relative filesystem path source against the config file's
+/// directory, like allowlist files. URLs, `file://` URLs and `-` (stdin)
+/// are left as they are.
+fn resolve_source_path(mut source: Source, base_dir: &Path) -> Source {
+    if source.url != "-" && !source.url.contains("://") && Path::new(&source.url).is_relative() {
+        source.url = base_dir.join(&source.url).to_string_lossy().into_owned();
+    }
+    source
+}
+
+/// Load the [allowlist], [denylist], [output], [cache] and [[routers]]
+/// sections into RunOptions.
+///
+/// Unlike sources, these have no defaults: a missing config.toml or section
+/// simply means no local overrides. Relative file paths are resolved against
+/// the directory containing the config file, so a config mounted into
+/// Docker can reference list files mounted next to it.
+fn load_options(config: Option<&Config>, config_path: &Path) -> io::Result<RunOptions> {
+    let Some(config) = config else {
+        return Ok(RunOptions::default());
+    };
+    let base_dir = config_path.parent().unwrap_or(Path::new(""));
+    let defaults = RunOptions::default();
+    let mut output = config.output.clone();
+    Ok(RunOptions {
+        allowlist: load_domain_list(config.allowlist.clone(), base_dir, "allowlist")?,
+        denylist: load_domain_list(config.denylist.clone(), base_dir, "denylist")?,
+        prune_subdomains: output
+            .as_ref()
+            .and_then(|o| o.prune_subdomains)
+            .unwrap_or(defaults.prune_subdomains),
+        max_bytes_per_file: output.as_ref().and_then(|o| o.max_bytes_per_file),
+        max_entries_per_file: output.as_ref().and_then(|o| o.max_entries_per_file),
+        entry_format: parse_sinkhole(output.as_ref().and_then(|o| o.sinkhole.as_deref()))
+            .map_err(|e| invalid_config(config_path, format_args!("[output] {e}")))?,
+        max_change_percent: output.as_ref().and_then(|o| o.max_change_percent),
+        compression: output
+            .as_mut()
+            .and_then(|o| o.compress.take())
+            .unwrap_or_default(),
+        header: load_header(output.as_mut().and_then(|o| o.header.take()), base_dir)?,
+        formats: output
+            .as_mut()
+            .and_then(|o| o.formats.take())
+            .unwrap_or_default()
+            .into_iter()
+            .map(FormatOutput::from)
+            .collect(),
+        categories: output
+            .as_mut()
+            .and_then(|o| o.categories.take())
+            .map(CategoryOptions::from),
+        rsc: output.and_then(|o| o.rsc).map(RscOptions::from),
+        cache_dir: config
+            .cache
+            .as_ref()
+            .and_then(|c| c.dir.as_ref())
+            .map(|dir| base_dir.join(dir)),
+        max_staleness: config
+            .cache
+            .as_ref()
+            .and_then(|c| c.max_staleness_hours)
+            .map(|hours| Duration::from_secs(hours * 3_600))
+            .unwrap_or(defaults.max_staleness),
+        routers: load_routers(config.routers.clone().unwrap_or_default()),
+        ..defaults
+    })
+}
+
+/// Build the [[routers]] list. A router without a url, whose password_env
+/// variable is not set, or that uploads without a files_url, is reported and
+/// skipped.
+fn load_routers(routers: Vec<RouterConfig>) -> Vec<Router> {
+    let mut loaded = Vec::with_capacity(routers.len());
+    for router in routers {
+        let Some(url) = router.url else {
+            eprintln!("Warning: ignoring [[routers]] entry without a url");
+            continue;
+        };
+        let password = match router.password_env {
+            Some(var) => match env::var(&var) {
+                Ok(password) => password,
+                Err(_) => {
+                    eprintln!("Warning: {var} is not set, not deploying to {url}");
+                    continue;
+                }
+            },
+            None => router.password.unwrap_or_default(),
+        };
+        let action = router.action.unwrap_or_default();
+        if action == DeployAction::Upload && router.files_url.is_none() {
+            eprintln!("Warning: upload to {url} needs a files_url, not deploying to it");
+            continue;
+        }
+        loaded.push(Router {
+            url,
+            user: router.user.unwrap_or_else(|| "admin".to_string()),
+            password,
+            action,
+            files_url: router.files_url,
+            accept_invalid_certs: router.accept_invalid_certs.unwrap_or(false),
+        });
+    }
+    loaded
+}
+
+/// Load the [daemon] schedule, defaulting to every 6 hours with up to 10
+/// minutes of jitter.
+fn load_schedule(config: Option<&Config>) -> Schedule {
+    let defaults = Schedule::default();
+    let Some(daemon) = config.and_then(|config| config.daemon.as_ref()) else {
+        return defaults;
+    };
+    let minutes = |m: u64| Duration::from_secs(m * 60);
+    Schedule {
+        // A zero interval would rebuild in a tight loop
+        interval: daemon
+            .interval_minutes
+            .filter(|&m| m > 0)
+            .map_or(defaults.interval, minutes),
+        jitter: daemon.jitter_minutes.map_or(defaults.jitter, minutes),
+    }
+}
+
+/// Load the [serve] listen address, defaulting to port 8080 on all
+/// interfaces. An invalid address is an error like an invalid config:
+/// falling back to the default would expose a server meant for one
+/// interface on all of them.
+fn load_listen(config: Option<&Config>, config_path: &Path) -> io::Result<SocketAddr> {
+    let Some(listen) = config
+        .and_then(|config| config.serve.as_ref())
+        .and_then(|serve| serve.listen.as_ref())
+    else {
+        return Ok(SocketAddr::from(([0, 0, 0, 0], 8080)));
+    };
+    listen.parse().map_err(|e| {
+        invalid_config(
+            config_path,
+            format_args!("[serve] listen address {listen}: {e}"),
+        )
+    })
+}
+
+/// Completes on SIGTERM (`docker stop`) or Ctrl-C.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Build the header template from [output.header], starting from the
+/// defaults. An unreadable template file is an error, like an invalid
+/// setting: building with the default header would drop the user's layout.
+fn load_header(header: Option<Header>, base_dir: &Path) -> io::Result<HeaderTemplate> {
+    let defaults = HeaderTemplate::default();
+    let Some(header) = header else {
+        return Ok(defaults);
+    };
+    let template = match (header.template, header.template_file) {
+        (Some(template), _) => template,
+        (None, Some(file)) => {
+            let path = base_dir.join(file);
+            std::fs::read_to_string(&path).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("failed to read header template {}: {e}", path.display()),
+                )
+            })?
+        }
+        (None, None) => defaults.template,
+    };
+    Ok(HeaderTemplate {
+        template,
+        title: header.title.unwrap_or(defaults.title),
+        url: header.url.unwrap_or(defaults.url),
+        homepage: header.homepage.unwrap_or(defaults.homepage),
+        license: header.license.unwrap_or(defaults.license),
+        sources_heading: header.sources_heading.unwrap_or(defaults.sources_heading),
+    })
+}
+
+/// Parse the [output] sinkhole setting, defaulting to `0.0.0.0`.
+fn parse_sinkhole(sinkhole: Option<&str>) -> anyhow::Result<EntryFormat> {
+    sinkhole.map_or(Ok(EntryFormat::default()), str::parse)
+}
+
+/// Flatten a DomainList into raw entries: inline domains first, then the
+/// lines of each file in order. Validation happens in the library. An
+/// unreadable file is an error, like an unreadable header template: building
+/// without it would re-block allowlisted domains and unblock denylisted ones.
+fn load_domain_list(
+    list: Option<DomainList>,
+    base_dir: &Path,
+    kind: &str,
+) -> io::Result<Vec<String>> {
+    let Some(list) = list else {
+        return Ok(Vec::new());
+    };
+    let mut entries = list.domains.unwrap_or_default();
+    for file in list.files.unwrap_or_default() {
+        let path = base_dir.join(&file);
+        let content = std::fs::read_to_string(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to read {kind} file {}: {e}", path.display()),
+            )
+        })?;
+        entries.extend(content.lines().map(str::to_string));
+    }
+    if !entries.is_empty() {
+        info(format_args!("Loaded {} {kind} entries", entries.len()));
+    }
+    Ok(entries)
+}
+
+/// What a command runs with, loaded from config.toml and the command line.
+#[derive(Debug)]
+struct Run {
+    sources: Vec<Source>,
+    options: RunOptions,
+    schedule: Schedule,
+    listen: SocketAddr,
+}
+
+/// Parse config.toml once and load everything the command runs with,
+/// applying the command-line flags. --source replaces the configured
+/// sources; the rest of the config (allowlist, output settings, cache)
+/// still applies, so config.toml is parsed either way and `check` fails on
+/// a broken one, including its [daemon] and [serve] sections.
+fn load_run(cli: &cli::Cli) -> io::Result<Run> {
+    let config = read_config(&cli.config, cli.command == Command::Check)?;
+    let sources = if cli.sources.is_empty() {
+        load_config(config.as_ref(), &cli.config)
+    } else {
+        cli.sources.iter().cloned().map(Source::from).collect()
+    };
+    let mut options = load_options(config.as_ref(), &cli.config)?;
+    if options.categories.is_some() {
+        check_categories(sources.iter().filter_map(|s| s.category.as_deref()))
+            .map_err(|e| invalid_config(&cli.config, e))?;
+    }
+    options.force = cli.force;
+    options.output_dir = cli.output.clone();
+    options.verbosity = cli.verbosity;
+    options.dry_run = cli.command == Command::Check;
+    options.report = cli.report;
+    options.report_path = cli.report_path.clone();
+    let listen = match cli.listen {
+        Some(addr) => addr,
+        None => load_listen(config.as_ref(), &cli.config)?,
+    };
+    Ok(Run {
+        sources,
+        options,
+        schedule: load_schedule(config.as_ref()),
+        listen,
+    })
+}
+
+/// The per-user download cache, `$XDG_CACHE_HOME/adblock2mikrotik_rust` or
+/// else `~/.cache/adblock2mikrotik_rust`, or None (no cache, with a warning)
+/// if there is no usable one. Never a shared directory such as the temp dir
+/// (see RunOptions::cache_dir).
+fn default_cache_dir() -> Option<PathBuf> {
+    let absolute = |path: PathBuf| path.is_absolute().then_some(path);
+    let base = env::var_os("XDG_CACHE_HOME")
+        .and_then(|dir| absolute(dir.into()))
+        .or_else(|| absolute(PathBuf::from(env::var_os("HOME")?).join(".cache")));
+    let Some(base) = base else {
+        eprintln!("Warning: neither XDG_CACHE_HOME nor HOME is set, not caching downloads");
+        return None;
+    };
+    let dir = base.join("adblock2mikrotik_rust");
+    match create_private_dir(&dir) {
+        Ok(()) => Some(dir),
+        Err(e) => {
+            eprintln!("Warning: not caching downloads in {}: {e}", dir.display());
+            None
+        }
+    }
+}
+
+/// Create `dir` readable by the current user only (0700), or check that an
+/// existing one is a directory owned by the current user and not writable
+/// by anyone else.
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    if let Some(parent) = dir.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    match builder.create(dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    // Not following symlinks: a link planted in place of the directory is
+    // refused like any other non-directory
+    let metadata = std::fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() {
+        return Err(io::Error::other("not a directory"));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        // SAFETY: geteuid has no preconditions and always succeeds
+        if metadata.uid() != unsafe { libc::geteuid() } {
+            return Err(io::Error::other("owned by another user"));
+        }
+        if metadata.mode() & 0o022 != 0 {
+            return Err(io::Error::other("writable by other users"));
+        }
+    }
+    Ok(())
+}
+
+/// Report a config.toml that can't be read or parsed and exit with status 2,
+/// like a command-line error (and unlike `lookup`'s "not blocked" status 1).
+fn config_error(e: io::Error) -> ! {
+    eprintln!("Error: {e}");
+    std::process::exit(2);
+}
+
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    // Check for version flag before loading config to avoid unnecessary file I/O
-    let args: Vec<String> = env::args().collect();
-    if args
-        .iter()
-        .any(|arg| arg == "--version" || arg == "-v" || arg == "-V")
-    {
-        println!("adblock2mikrotik_rust v{}", env!("CARGO_PKG_VERSION"));
-        return Ok(());
+    let cli = match cli::parse(env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("Error: {e}\n\n{}", cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    // Handle help and version before loading config to avoid unnecessary file I/O
+    match cli.command {
+        Command::Help => {
+            print!("{}", cli::USAGE);
+            return Ok(());
+        }
+        Command::Version => {
+            println!("adblock2mikrotik_rust v{}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        Command::Build | Command::Check | Command::Lookup | Command::Daemon | Command::Serve => {}
+    }
+    QUIET.store(cli.verbosity == Verbosity::Quiet, Ordering::Relaxed);
+
+    let Run {
+        sources,
+        mut options,
+        schedule,
+        listen,
+    } = load_run(&cli).unwrap_or_else(|e| config_error(e));
+    // The cache is what keeps a briefly unreachable source's domains in the
+    // list (and, between daemon builds, the only state that survives), so
+    // default to a per-user one when none is configured
+    if options.cache_dir.is_none() {
+        options.cache_dir = default_cache_dir();
     }
 
-    let urls = load_config(Path::new(CONFIG_PATH));
-    let url_refs: Vec<&str> = urls.iter().map(|s| s.as_str()).collect();
-    run(url_refs).await
+    if let Some(domain) = cli.domain {
+        // Like grep: exit status 0 when the domain is blocked, 1 when not,
+        // 2 on errors
+        match lookup(sources, &domain, &options).await {
+            Ok(report) => {
+                print!("{report}");
+                std::process::exit(if report.is_blocked() { 0 } else { 1 });
+            }
+            Err(e) => {
+                eprintln!("Error: {e}");
+                std::process::exit(2);
+            }
+        }
+    }
+    if matches!(cli.command, Command::Daemon | Command::Serve) {
+        if cli.command == Command::Serve {
+            let served =
+                serve_with_options(sources, &options, &schedule, listen, shutdown_signal()).await;
+            if let Err(e) = served {
+                eprintln!("Error: {e}");
+                std::process::exit(1);
+            }
+        } else {
+            let ran =
+                run_daemon_with_options(sources, &options, &schedule, shutdown_signal()).await;
+            if let Err(e) = ran {
+                eprintln!("Error: {e}");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    if let Err(e) = run_with_options(sources, &options).await {
+        eprintln!("Error: {e}");
+        std::process::exit(1);
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::fs;
+    use std::path::PathBuf;
     use std::sync::OnceLock;
     use tempfile::tempdir;
     use tokio::sync::Mutex;
@@ -118,7 +822,7 @@ mod tests {
     // in case a future test in this module does the same concurrently. The
     // load_config tests below no longer need any locking or cleanup: each
     // uses its own isolated tempdir and passes the path directly to
-    // load_config(), so there's no shared file for parallel test threads to
+    // sources_at(), so there's no shared file for parallel test threads to
     // race on.
     //
     // tokio::sync::Mutex (not std::sync::Mutex) is used deliberately: its
@@ -132,6 +836,29 @@ mod tests {
         ENV_LOCK.get_or_init(|| Mutex::new(()))
     }
 
+    // Each reads the config file at `path`, then loads from it like load_run
+
+    fn sources_at(path: &Path) -> io::Result<Vec<Source>> {
+        Ok(load_config(read_config(path, false)?.as_ref(), path))
+    }
+
+    fn options_at(path: &Path) -> io::Result<RunOptions> {
+        load_options(read_config(path, false)?.as_ref(), path)
+    }
+
+    fn schedule_at(path: &Path) -> io::Result<Schedule> {
+        Ok(load_schedule(read_config(path, false)?.as_ref()))
+    }
+
+    fn listen_at(path: &Path) -> io::Result<SocketAddr> {
+        load_listen(read_config(path, false)?.as_ref(), path)
+    }
+
+    /// Parses the config file at `path` like `check` does.
+    fn check_at(path: &Path) -> io::Result<Option<Config>> {
+        read_config(path, true)
+    }
+
     #[tokio::test]
     async fn test_run_no_rules_no_file_written() {
         let _guard = get_env_lock().lock().await;
@@ -141,7 +868,7 @@ mod tests {
         // mutates process environment variables concurrently.
         unsafe { std::env::set_var("OUTPUT_DIR", temp_dir.path()) };
 
-        let result = run(vec![]).await;
+        let result = adblock2mikrotik_rust::run(vec![]).await;
 
         // SAFETY: same guard as above.
         unsafe { std::env::remove_var("OUTPUT_DIR") };
@@ -156,7 +883,7 @@ mod tests {
     #[test]
     fn test_load_config_fallback_when_no_config() {
         let dir = tempdir().unwrap();
-        let urls = load_config(&dir.path().join("nonexistent_config.toml"));
+        let urls = sources_at(&dir.path().join("nonexistent_config.toml")).unwrap();
         assert_eq!(urls, default_sources());
     }
 
@@ -172,10 +899,10 @@ urls = [
 ]
 "#;
         fs::write(&config_path, toml_content).unwrap();
-        let urls = load_config(&config_path);
+        let urls = sources_at(&config_path).unwrap();
         assert_eq!(urls.len(), 2);
-        assert_eq!(urls[0], "https://example.com/list1.txt");
-        assert_eq!(urls[1], "https://example.com/list2.txt");
+        assert_eq!(urls[0].url, "https://example.com/list1.txt");
+        assert_eq!(urls[1].url, "https://example.com/list2.txt");
     }
 
     #[test]
@@ -183,10 +910,74 @@ urls = [
         let dir = tempdir().unwrap();
         let config_path = dir.path().join("config.toml");
         fs::write(&config_path, "this is not valid toml [[[").unwrap();
-        let urls = load_config(&config_path);
+        let urls = sources_at(&config_path).unwrap();
         assert_eq!(urls, default_sources());
     }
 
+    #[test]
+    fn test_check_rejects_invalid_toml() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(&config_path, "this is not valid toml [[[").unwrap();
+        let err = check_at(&config_path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("config.toml"), "{err}");
+
+        let mut cli = cli::Cli {
+            command: Command::Check,
+            config: config_path,
+            ..cli::Cli::default()
+        };
+        assert!(load_run(&cli).is_err());
+        cli.command = Command::Build;
+        assert_eq!(load_run(&cli).unwrap().sources, default_sources());
+    }
+
+    #[test]
+    fn test_check_rejects_unknown_keys() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        for (content, key) in [
+            ("[output]\nmax_change_precent = 10\n", "max_change_precent"),
+            ("[sources]\nurl = [\"https://example.com\"]\n", "url"),
+            (
+                "[[sources]]\nurl = \"https://example.com\"\nenable = false\n",
+                "enable",
+            ),
+            ("[output.header]\ntittle = \"My list\"\n", "tittle"),
+            (
+                "[[routers]]\nurl = \"https://192.168.88.1\"\npasword = \"x\"\n",
+                "pasword",
+            ),
+            ("[cahce]\ndir = \".cache\"\n", "cahce"),
+        ] {
+            fs::write(&config_path, content).unwrap();
+            let err = check_at(&config_path).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains(key), "{err}");
+            // A build only warns and skips the key
+            assert!(options_at(&config_path).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_check_rejects_invalid_config() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(&config_path, "[output]\nmax_change_percent = \"ten\"\n").unwrap();
+        let mut cli = cli::Cli {
+            command: Command::Check,
+            config: config_path,
+            ..cli::Cli::default()
+        };
+        let err = load_run(&cli).unwrap_err();
+        assert!(err.to_string().contains("max_change_percent"), "{err}");
+
+        // --source skips the configured sources, not the config check
+        cli.sources = vec!["https://example.com/list.txt".to_string()];
+        assert!(load_run(&cli).is_err());
+    }
+
     #[test]
     fn test_load_config_empty_array() {
         // Explicit `urls = []` is an intentional override — convert nothing —
@@ -198,7 +989,7 @@ urls = [
 urls = []
 "#;
         fs::write(&config_path, toml_content).unwrap();
-        let urls = load_config(&config_path);
+        let urls = sources_at(&config_path).unwrap();
         assert_eq!(urls.len(), 0);
     }
 
@@ -211,7 +1002,7 @@ urls = []
         let dir = tempdir().unwrap();
         let config_path = dir.path().join("config.toml");
         fs::write(&config_path, "[sources]\n# no urls key here\n").unwrap();
-        let urls = load_config(&config_path);
+        let urls = sources_at(&config_path).unwrap();
         assert_eq!(urls, default_sources());
     }
 
@@ -224,13 +1015,115 @@ urls = []
 urls = ["https://custom.com/blocklist.txt"]
 "#;
         fs::write(&config_path, toml_content).unwrap();
-        let urls = load_config(&config_path);
+        let urls = sources_at(&config_path).unwrap();
         assert_ne!(
             urls,
             default_sources(),
             "Config should override default sources"
         );
-        assert_eq!(urls[0], "https://custom.com/blocklist.txt");
+        assert_eq!(urls[0].url, "https://custom.com/blocklist.txt");
+    }
+
+    #[test]
+    fn test_load_config_source_tables() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        let toml_content = r#"
+[[sources]]
+url = "https://example.com/pro.txt"
+name = "pro"
+category = "ads"
+required = true
+
+[[sources]]
+url = "https://example.com/hosts"
+format = "hosts"
+
+[[sources]]
+url = "https://example.com/paused.txt"
+enabled = false
+
+[[sources]]
+name = "no url"
+"#;
+        fs::write(&config_path, toml_content).unwrap();
+        let sources = sources_at(&config_path).unwrap();
+        assert_eq!(
+            sources,
+            vec![
+                Source {
+                    url: "https://example.com/pro.txt".to_string(),
+                    name: Some("pro".to_string()),
+                    category: Some("ads".to_string()),
+                    required: true,
+                    ..Default::default()
+                },
+                Source {
+                    url: "https://example.com/hosts".to_string(),
+                    format: Some(SourceFormat::Hosts),
+                    ..Default::default()
+                },
+            ]
+        );
+
+        // All disabled is an explicit choice, like `urls = []`
+        fs::write(
+            &config_path,
+            "[[sources]]\nurl = \"https://example.com/a.txt\"\nenabled = false\n",
+        )
+        .unwrap();
+        assert!(sources_at(&config_path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_check_reports_source_field_errors() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            "[[sources]]\nurl = \"https://example.com/hosts\"\nformat = \"hostz\"\n",
+        )
+        .unwrap();
+        let err = check_at(&config_path).err().unwrap().to_string();
+        assert!(err.contains("unknown variant `hostz`"), "{err}");
+
+        fs::write(
+            &config_path,
+            "[sources]\nurls = \"https://example.com/a.txt\"\n",
+        )
+        .unwrap();
+        let err = check_at(&config_path).err().unwrap().to_string();
+        assert!(err.contains("invalid type: string"), "{err}");
+        assert!(!err.contains("did not match any variant"), "{err}");
+    }
+
+    #[test]
+    fn test_load_config_resolves_local_paths() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        let toml_content = r#"
+[sources]
+urls = ["https://example.com/a.txt", "lists/inhouse.txt", "/srv/b.txt", "file:///srv/c.txt", "-"]
+"#;
+        fs::write(&config_path, toml_content).unwrap();
+        let urls: Vec<String> = sources_at(&config_path)
+            .unwrap()
+            .into_iter()
+            .map(|source| source.url)
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                "https://example.com/a.txt".to_string(),
+                dir.path()
+                    .join("lists/inhouse.txt")
+                    .to_string_lossy()
+                    .into_owned(),
+                "/srv/b.txt".to_string(),
+                "file:///srv/c.txt".to_string(),
+                "-".to_string(),
+            ]
+        );
     }
 
     #[test]
@@ -243,7 +1136,7 @@ urls = ["https://custom.com/blocklist.txt"]
             !urls.is_empty(),
             "config.toml.example must define at least one [sources] url"
         );
-        assert!(urls.iter().all(|u| u.starts_with("https://")));
+        assert!(urls.iter().all(|u| u.url.starts_with("https://")));
     }
 
     #[test]
@@ -259,9 +1152,9 @@ urls = [
 ]
 "#;
         fs::write(&config_path, toml_content).unwrap();
-        let urls = load_config(&config_path);
+        let urls = sources_at(&config_path).unwrap();
         assert_eq!(urls.len(), 2);
-        assert_eq!(urls[0], "https://example.com/list1.txt");
+        assert_eq!(urls[0].url, "https://example.com/list1.txt");
     }
 
     #[test]
@@ -277,7 +1170,394 @@ urls = [
 ]
 "#;
         fs::write(&config_path, toml_content).unwrap();
-        let urls = load_config(&config_path);
+        let urls = sources_at(&config_path).unwrap();
         assert_eq!(urls.len(), 3, "Should preserve duplicate URLs from config");
     }
+
+    #[test]
+    fn test_load_options_without_config_is_empty() {
+        let dir = tempdir().unwrap();
+        let options = options_at(&dir.path().join("nonexistent_config.toml")).unwrap();
+        assert!(options.allowlist.is_empty());
+        assert!(options.denylist.is_empty());
+    }
+
+    #[test]
+    fn test_load_options_inline_and_file_entries() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(
+            dir.path().join("allow.txt"),
+            "# team allowlist\nsaas.example.com\n",
+        )
+        .unwrap();
+        let toml_content = r#"
+[allowlist]
+domains = ["broken.example.org"]
+files = ["allow.txt"]
+
+[denylist]
+domains = ["phishing.example.net"]
+"#;
+        fs::write(&config_path, toml_content).unwrap();
+        let options = options_at(&config_path).unwrap();
+        assert_eq!(
+            options.allowlist,
+            vec!["broken.example.org", "# team allowlist", "saas.example.com"]
+        );
+        assert_eq!(options.denylist, vec!["phishing.example.net"]);
+        assert!(options.prune_subdomains, "pruning is on unless disabled");
+
+        fs::write(&config_path, "[denylist]\nfiles = [\"missing.txt\"]\n").unwrap();
+        let err = options_at(&config_path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("denylist file"));
+    }
+
+    #[test]
+    fn test_load_options_disable_prune_subdomains() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            "[output]\nprune_subdomains = false\nmax_entries_per_file = 50000\n",
+        )
+        .unwrap();
+        let options = options_at(&config_path).unwrap();
+        assert!(!options.prune_subdomains);
+        assert_eq!(options.max_change_percent, None);
+        assert_eq!(options.max_entries_per_file, Some(50_000));
+        assert_eq!(options.max_bytes_per_file, None);
+    }
+
+    #[test]
+    fn test_load_options_sinkhole() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(&config_path, "[output]\nsinkhole = \"::\"\n").unwrap();
+        assert_eq!(
+            options_at(&config_path).unwrap().entry_format,
+            "ipv6".parse::<EntryFormat>().unwrap()
+        );
+
+        fs::write(&config_path, "[output]\nsinkhole = \"none\"\n").unwrap();
+        assert_eq!(
+            options_at(&config_path).unwrap().entry_format,
+            EntryFormat::DomainOnly
+        );
+
+        fs::write(&config_path, "[output]\nsinkhole = \"not-an-ip\"\n").unwrap();
+        let err = options_at(&config_path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("invalid sinkhole \"not-an-ip\""));
+    }
+
+    #[test]
+    fn test_load_options_cache_dir() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            "[cache]\ndir = \".cache\"\nmax_staleness_hours = 48\n",
+        )
+        .unwrap();
+        let options = options_at(&config_path).unwrap();
+        // Relative to the config file, like allowlist files
+        assert_eq!(options.cache_dir, Some(dir.path().join(".cache")));
+        assert_eq!(options.max_staleness, Duration::from_secs(48 * 3_600));
+
+        fs::write(&config_path, "[cache]\ndir = \"/var/cache/adblock\"\n").unwrap();
+        assert_eq!(
+            options_at(&config_path).unwrap().cache_dir,
+            Some(PathBuf::from("/var/cache/adblock"))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_private_dir() {
+        use std::os::unix::fs::{PermissionsExt, symlink};
+        let dir = tempdir().unwrap();
+        let cache = dir.path().join("cache/adblock2mikrotik_rust");
+        create_private_dir(&cache).unwrap();
+        let mode = fs::metadata(&cache).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        // An existing private directory is reused
+        create_private_dir(&cache).unwrap();
+
+        fs::set_permissions(&cache, fs::Permissions::from_mode(0o777)).unwrap();
+        let err = create_private_dir(&cache).unwrap_err();
+        assert!(err.to_string().contains("writable by other users"));
+
+        let link = dir.path().join("link");
+        symlink(&cache, &link).unwrap();
+        assert!(create_private_dir(&link).is_err());
+    }
+
+    #[test]
+    fn test_load_options_rsc_section() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(&config_path, "[output.rsc]\nmode = \"address\"\n").unwrap();
+        let rsc = options_at(&config_path)
+            .unwrap()
+            .rsc
+            .expect("[output.rsc] enables the script");
+        assert_eq!(rsc.mode, RscMode::Address);
+        assert_eq!(rsc.file_name, "hosts.rsc");
+        assert!(rsc.match_subdomain);
+
+        fs::write(&config_path, "[output]\nprune_subdomains = true\n").unwrap();
+        assert!(options_at(&config_path).unwrap().rsc.is_none());
+    }
+
+    #[test]
+    fn test_load_options_rejects_unknown_rsc_mode() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(&config_path, "[output.rsc]\nmode = \"nxdomian\"\n").unwrap();
+        let err = check_at(&config_path).err().unwrap();
+        assert!(
+            err.to_string().contains("unknown variant `nxdomian`"),
+            "{err}"
+        );
+        assert!(sources_at(&config_path).is_ok(), "a build falls back");
+    }
+
+    #[test]
+    fn test_load_options_formats() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            "[[output.formats]]\nformat = \"dnsmasq\"\n\n[[output.formats]]\nformat = \"rpz\"\nfile = \"block.rpz\"\n",
+        )
+        .unwrap();
+        let options = options_at(&config_path).unwrap();
+        assert!(options.compression.is_empty());
+        let formats = options.formats;
+        assert_eq!(
+            formats,
+            [
+                FormatOutput {
+                    format: ListFormat::Dnsmasq,
+                    file_name: "dnsmasq.conf".to_string(),
+                },
+                FormatOutput {
+                    format: ListFormat::Rpz,
+                    file_name: "block.rpz".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_options_rejects_unknown_format() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(&config_path, "[[output.formats]]\nformat = \"bind\"\n").unwrap();
+        let err = check_at(&config_path).err().unwrap();
+        assert!(err.to_string().contains("unknown variant `bind`"), "{err}");
+    }
+
+    #[test]
+    fn test_load_options_compression() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(&config_path, "[output]\ncompress = [\"gzip\", \"zstd\"]\n").unwrap();
+        assert_eq!(
+            options_at(&config_path).unwrap().compression,
+            [Compression::Gzip, Compression::Zstd]
+        );
+
+        fs::write(&config_path, "[output]\ncompress = [\"brotli\"]\n").unwrap();
+        let err = check_at(&config_path).err().unwrap();
+        assert!(
+            err.to_string().contains("unknown variant `brotli`"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_load_options_categories_section() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            "[output.categories]\npriority = [\"gambling\"]\n",
+        )
+        .unwrap();
+        let categories = options_at(&config_path)
+            .unwrap()
+            .categories
+            .expect("[output.categories] enables per-category files");
+        assert!(categories.combined);
+        assert_eq!(categories.priority, ["gambling"]);
+
+        fs::write(&config_path, "[output]\nprune_subdomains = true\n").unwrap();
+        assert!(options_at(&config_path).unwrap().categories.is_none());
+    }
+
+    #[test]
+    fn test_load_run_rejects_colliding_categories() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        let config = r#"
+[[sources]]
+url = "https://example.com/a.txt"
+category = "ads/trackers"
+
+[[sources]]
+url = "https://example.com/b.txt"
+category = "ads-trackers"
+"#;
+        fs::write(&config_path, config).unwrap();
+        let cli = cli::Cli {
+            command: Command::Check,
+            config: config_path.clone(),
+            ..cli::Cli::default()
+        };
+        // Only a problem once categories get their own files
+        assert!(load_run(&cli).is_ok());
+
+        fs::write(&config_path, format!("{config}\n[output.categories]\n")).unwrap();
+        let err = load_run(&cli).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("hosts-ads-trackers.txt"), "{err}");
+    }
+
+    #[test]
+    fn test_load_options_routers() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        fs::write(
+            &config_path,
+            r#"
+[[routers]]
+url = "https://192.168.88.1"
+user = "api"
+password = "secret"
+action = "upload"
+files_url = "http://192.168.88.10:8080"
+accept_invalid_certs = true
+
+[[routers]]
+url = "https://10.0.0.1"
+password_env = "ADBLOCK2MIKROTIK_TEST_UNSET_PASSWORD"
+
+[[routers]]
+url = "https://10.0.0.2"
+action = "upload"
+
+[[routers]]
+user = "no-url"
+"#,
+        )
+        .unwrap();
+        let routers = options_at(&config_path).unwrap().routers;
+        // The router whose password variable is unset, the upload without a
+        // files_url and the one without a url are skipped
+        assert_eq!(
+            routers,
+            vec![Router {
+                url: "https://192.168.88.1".to_string(),
+                user: "api".to_string(),
+                password: "secret".to_string(),
+                action: DeployAction::Upload,
+                files_url: Some("http://192.168.88.10:8080".to_string()),
+                accept_invalid_certs: true,
+            }]
+        );
+
+        fs::write(
+            &config_path,
+            "[[routers]]\nurl = \"https://192.168.88.1\"\naction = \"reboot\"\n",
+        )
+        .unwrap();
+        let err = check_at(&config_path).err().unwrap();
+        assert!(
+            err.to_string().contains("unknown variant `reboot`"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_load_listen() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        assert_eq!(
+            listen_at(&config_path).unwrap(),
+            "0.0.0.0:8080".parse().unwrap()
+        );
+
+        fs::write(&config_path, "[serve]\nlisten = \"[::]:8081\"\n").unwrap();
+        assert_eq!(
+            listen_at(&config_path).unwrap(),
+            "[::]:8081".parse().unwrap()
+        );
+
+        for listen in ["localhost", "127.0.0.1:80800"] {
+            fs::write(&config_path, format!("[serve]\nlisten = \"{listen}\"\n")).unwrap();
+            let err = listen_at(&config_path).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains(listen));
+        }
+    }
+
+    #[test]
+    fn test_load_schedule() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        assert_eq!(schedule_at(&config_path).unwrap(), Schedule::default());
+
+        fs::write(
+            &config_path,
+            "[daemon]\ninterval_minutes = 90\njitter_minutes = 0\n",
+        )
+        .unwrap();
+        let schedule = schedule_at(&config_path).unwrap();
+        assert_eq!(schedule.interval, Duration::from_secs(90 * 60));
+        assert_eq!(schedule.jitter, Duration::ZERO);
+
+        fs::write(&config_path, "[daemon]\ninterval_minutes = 0\n").unwrap();
+        assert_eq!(
+            schedule_at(&config_path).unwrap().interval,
+            Schedule::default().interval
+        );
+    }
+
+    #[test]
+    fn test_load_options_header_section() {
+        let dir = tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        assert_eq!(
+            options_at(&config_path).unwrap().header,
+            HeaderTemplate::default()
+        );
+
+        fs::write(dir.path().join("header.txt"), "# {title}\n").unwrap();
+        let toml_content = r#"
+[output.header]
+title = "My list"
+homepage = "https://example.com"
+sources_heading = "Sources:"
+template_file = "header.txt"
+"#;
+        fs::write(&config_path, toml_content).unwrap();
+        let header = options_at(&config_path).unwrap().header;
+        assert_eq!(header.title, "My list");
+        assert_eq!(header.homepage, "https://example.com");
+        assert_eq!(header.sources_heading, "Sources:");
+        assert_eq!(header.template, "# {title}\n");
+        assert_eq!(header.license, HeaderTemplate::default().license);
+
+        fs::write(
+            &config_path,
+            "[output.header]\ntemplate_file = \"missing.txt\"\n",
+        )
+        .unwrap();
+        let err = options_at(&config_path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("missing.txt"));
+    }
 }
@@ -0,0 +1,998 @@
+//! Rendering and writing of the generated files.
+
+use crate::{EntryFormat, SourceOutput, format_with_commas};
+use chrono::Utc;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// Entry type used for each `/ip dns static` record in the `.rsc` script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RscMode {
+    /// `type=NXDOMAIN`: the router answers "no such domain" (RouterOS 7).
+    Nxdomain,
+    /// `address=0.0.0.0`: a sinkhole A record, also understood by RouterOS 6.
+    /// Uses the configured sinkhole address; an IPv6 one becomes an AAAA
+    /// record.
+    Address,
+}
+
+/// Settings for the RouterOS `.rsc` import script written next to hosts.txt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RscOptions {
+    /// File name of the script, in the same directory as hosts.txt.
+    pub file_name: String,
+    pub mode: RscMode,
+    /// Add `match-subdomain=yes` so each record also covers subdomains, as an
+    /// adlist entry does. Requires RouterOS 7; disable it (together with
+    /// subdomain pruning) for RouterOS 6.
+    pub match_subdomain: bool,
+    /// Comment attached to every record. The script starts by removing all
+    /// static records carrying it, so re-importing replaces the previous
+    /// import instead of piling up duplicates.
+    pub comment: String,
+}
+
+/// Settings for [`crate::RunOptions::categories`]: one hosts file per
+/// [`crate::Source::category`], e.g. hosts-ads.txt and hosts-gambling.txt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CategoryOptions {
+    /// Also write the combined hosts.txt with every source. Sources without
+    /// a category and the local denylist are only written there.
+    pub combined: bool,
+    /// Which category keeps a domain listed under several: the first one
+    /// here does, and it is left out of the others, so a router loading
+    /// several category files holds each domain once. Categories not listed
+    /// follow in the order their first source appears.
+    pub priority: Vec<String>,
+}
+
+impl Default for CategoryOptions {
+    fn default() -> Self {
+        CategoryOptions {
+            combined: true,
+            priority: Vec::new(),
+        }
+    }
+}
+
+impl Default for RscOptions {
+    fn default() -> Self {
+        RscOptions {
+            file_name: "hosts.rsc".to_string(),
+            mode: RscMode::Nxdomain,
+            match_subdomain: true,
+            comment: "adblock2mikrotik".to_string(),
+        }
+    }
+}
+
+/// Syntax of a generated list file: the entries written for each domain
+/// and the marker of comment lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListFormat {
+    /// Hosts entries as set by [`EntryFormat`], e.g. `0.0.0.0 domain`, for
+    /// RouterOS adlists.
+    #[default]
+    Hosts,
+    /// `address=/domain/0.0.0.0` dnsmasq options (OpenWrt), with the
+    /// configured sinkhole address; bare domains give `address=/domain/`,
+    /// answered with NXDOMAIN.
+    Dnsmasq,
+    /// `local-zone: "domain" always_nxdomain` Unbound statements (pfSense,
+    /// OPNsense), to include in the `server:` clause.
+    Unbound,
+    /// BIND response policy zone: SOA and NS records, then `domain CNAME .`
+    /// and `*.domain CNAME .` (NXDOMAIN) for every domain. The SOA serial is
+    /// the build time in seconds, so each build increases it.
+    Rpz,
+    /// `||domain^` AdGuard Home rules.
+    Adguard,
+}
+
+impl ListFormat {
+    /// File name used when none is configured.
+    pub fn default_file_name(self) -> &'static str {
+        match self {
+            ListFormat::Hosts => "hosts.txt",
+            ListFormat::Dnsmasq => "dnsmasq.conf",
+            ListFormat::Unbound => "unbound.conf",
+            ListFormat::Rpz => "rpz.zone",
+            ListFormat::Adguard => "adguard.txt",
+        }
+    }
+
+    /// Marker starting a comment line.
+    fn comment(self) -> char {
+        match self {
+            ListFormat::Rpz => ';',
+            ListFormat::Adguard => '!',
+            ListFormat::Hosts | ListFormat::Dnsmasq | ListFormat::Unbound => '#',
+        }
+    }
+
+    /// Appends the line(s) blocking `domain`. `prefix` is
+    /// [`EntryFormat::prefix`], computed once per file.
+    fn push_entry(self, content: &mut String, domain: &str, prefix: &str) {
+        match self {
+            ListFormat::Hosts => {
+                content.push_str(prefix);
+                content.push_str(domain);
+            }
+            ListFormat::Dnsmasq => {
+                content.push_str("address=/");
+                content.push_str(domain);
+                content.push('/');
+                content.push_str(prefix.trim_end());
+            }
+            ListFormat::Unbound => {
+                content.push_str("local-zone: \"");
+                content.push_str(domain);
+                content.push_str("\" always_nxdomain");
+            }
+            ListFormat::Rpz => {
+                // Policy records only match the exact name; the wildcard
+                // covers subdomains, as adlist entries do
+                content.push_str(domain);
+                content.push_str(" CNAME .\n*.");
+                content.push_str(domain);
+                content.push_str(" CNAME .");
+            }
+            ListFormat::Adguard => {
+                content.push_str("||");
+                content.push_str(domain);
+                content.push('^');
+            }
+        }
+        content.push('\n'); // single char push — no format! allocation
+    }
+}
+
+/// An extra list file written next to hosts.txt, for another resolver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatOutput {
+    pub format: ListFormat,
+    /// File name, in the same directory as hosts.txt.
+    pub file_name: String,
+}
+
+impl From<ListFormat> for FormatOutput {
+    fn from(format: ListFormat) -> Self {
+        FormatOutput {
+            format,
+            file_name: format.default_file_name().to_string(),
+        }
+    }
+}
+
+/// Compressed copy written next to each generated file, e.g. hosts.txt.gz,
+/// for HTTP servers that serve precompressed files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Every compression, in the order `serve` prefers them.
+    pub(crate) const ALL: [Compression; 2] = [Compression::Zstd, Compression::Gzip];
+
+    /// The `Content-Encoding` token, also used for the config value.
+    pub fn encoding(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// File name of the compressed copy of `file_name`, e.g. hosts.txt.gz.
+    pub fn file_name(self, file_name: &str) -> String {
+        let extension = match self {
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+        };
+        format!("{file_name}.{extension}")
+    }
+
+    /// Compresses at the highest practical level: the files are compressed
+    /// once per build and downloaded many times.
+    pub(crate) fn compress(self, content: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(
+                    Vec::with_capacity(content.len() / 4),
+                    flate2::Compression::best(),
+                );
+                encoder.write_all(content)?;
+                encoder.finish()
+            }
+            Compression::Zstd => zstd::encode_all(content, 19),
+        }
+    }
+
+    /// Recognizes a compressed body by its magic bytes.
+    pub(crate) fn sniff(body: &[u8]) -> Option<Compression> {
+        if body.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if body.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Decompresses `body`, failing if it isn't valid or decompresses to
+    /// more than `limit` bytes.
+    pub(crate) fn decompress(self, body: &[u8], limit: u64) -> std::io::Result<Vec<u8>> {
+        let mut decoded = Vec::with_capacity(body.len() * 4);
+        let read = match self {
+            Compression::Gzip => flate2::read::MultiGzDecoder::new(body)
+                .take(limit + 1)
+                .read_to_end(&mut decoded)?,
+            Compression::Zstd => zstd::stream::read::Decoder::new(body)?
+                .take(limit + 1)
+                .read_to_end(&mut decoded)?,
+        };
+        if read as u64 > limit {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("decompresses to more than {limit} bytes"),
+            ));
+        }
+        Ok(decoded)
+    }
+}
+
+/// Appends the header with its comment lines switched to the marker of
+/// `format`.
+fn push_header(content: &mut String, header: &str, format: ListFormat) {
+    for line in header.lines() {
+        match line.strip_prefix('#') {
+            Some(rest) => {
+                content.push(format.comment());
+                content.push_str(rest);
+            }
+            None => content.push_str(line),
+        }
+        content.push('\n');
+    }
+}
+
+/// Appends the `# Format:` line describing the entries, e.g.
+/// `# Format: 0.0.0.0 domain.tld`, and for RPZ the zone's SOA and NS
+/// records.
+fn push_format_line(content: &mut String, format: ListFormat, prefix: &str) {
+    let comment = format.comment();
+    let mut example = String::new();
+    format.push_entry(&mut example, "domain.tld", prefix);
+    let example = example.lines().next().unwrap_or_default();
+    content.push_str(&format!("{comment} Format: {example}\n{comment}\n"));
+    if format == ListFormat::Rpz {
+        let serial = Utc::now().timestamp();
+        content.push_str(&format!(
+            "\n$TTL 300\n@ IN SOA localhost. hostmaster.localhost. {serial} 3600 600 604800 300\n@ IN NS localhost.\n"
+        ));
+    }
+}
+
+/// Renders a list file: the header, one `# Source:` section per source, and
+/// the `# Total unique domains:` trailer, in the syntax of `format`.
+pub(crate) fn render_list<'a>(
+    header: &str,
+    sources: impl IntoIterator<Item = &'a SourceOutput>,
+    total_unique: usize,
+    format: ListFormat,
+    entry: &EntryFormat,
+) -> String {
+    let prefix = entry.prefix();
+    // Pre-allocate content buffer: header + avg 35 bytes per domain entry
+    let mut content = String::with_capacity(header.len() + total_unique * 35);
+    push_header(&mut content, header, format);
+    push_format_line(&mut content, format, &prefix);
+
+    for SourceOutput { url, domains, .. } in sources {
+        push_section(&mut content, url, domains, format, &prefix);
+    }
+
+    content.push('\n');
+    content.push(format.comment());
+    content.push_str(" Total unique domains: ");
+    content.push_str(&total_unique.to_string());
+    content.push('\n');
+    content
+}
+
+/// Appends one `# Source:` section of entries.
+fn push_section(
+    content: &mut String,
+    url: &str,
+    domains: &[String],
+    format: ListFormat,
+    prefix: &str,
+) {
+    let comment = format.comment();
+    content.push('\n');
+    content.push(comment);
+    content.push_str(" Source: ");
+    content.push_str(url);
+    content.push_str("\n\n");
+    for domain in domains {
+        format.push_entry(content, domain, prefix);
+    }
+    content.push('\n');
+    content.push(comment);
+    content.push_str(" Converted ");
+    content.push_str(&format_with_commas(domains.len()));
+    content.push_str(" rules from this source\n\n");
+}
+
+/// Upper bound on the bytes a part adds around the shared header: the
+/// "# Part i of n" lines, the format line and the trailer.
+const PART_OVERHEAD: usize = 160;
+
+/// Upper bound on the bytes a `# Source:` section adds around its entries,
+/// excluding the URL itself.
+const SECTION_OVERHEAD: usize = 80;
+
+/// One size-capped file of a split hosts list.
+pub(crate) struct HostsPart {
+    pub(crate) content: String,
+    pub(crate) domains: usize,
+}
+
+/// Splits the hosts list into parts holding at most `max_entries` entries
+/// and `max_bytes` bytes each. Every part is a complete hosts file with the
+/// shared header, a `# Part i of n` line and its own `# Source:` sections; a
+/// source larger than one part continues in the next.
+///
+/// Byte sizes are checked against conservative estimates of the fixed
+/// overhead, so a part may end slightly below `max_bytes` but never above.
+/// Fails if `max_bytes` cannot fit the header plus a single entry.
+pub(crate) fn render_hosts_parts<'a>(
+    header: &str,
+    sources: impl IntoIterator<Item = &'a SourceOutput>,
+    max_bytes: Option<usize>,
+    max_entries: Option<usize>,
+    entry: &EntryFormat,
+) -> std::io::Result<Vec<HostsPart>> {
+    let prefix = entry.prefix();
+    let fixed = header.len() + PART_OVERHEAD;
+    let over_bytes = |bytes: usize| max_bytes.is_some_and(|max| bytes > max);
+
+    // First pass: group slices of each source into parts
+    let mut parts: Vec<Vec<(&str, &[String])>> = Vec::new();
+    let mut current: Vec<(&str, &[String])> = Vec::new();
+    let (mut bytes, mut entries) = (fixed, 0usize);
+
+    for SourceOutput { url, domains, .. } in sources {
+        let section = url.len() + SECTION_OVERHEAD;
+        let mut start = 0;
+        for (i, domain) in domains.iter().enumerate() {
+            let entry = prefix.len() + domain.len() + 1;
+            let opens_section = if i == start { section } else { 0 };
+            let full = max_entries.is_some_and(|max| entries >= max)
+                || over_bytes(bytes + opens_section + entry);
+            if full && entries > 0 {
+                if i > start {
+                    current.push((url, &domains[start..i]));
+                }
+                parts.push(std::mem::take(&mut current));
+                (bytes, entries, start) = (fixed, 0, i);
+            }
+
+            let needed = entry + if i == start { section } else { 0 };
+            if over_bytes(bytes + needed) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "max_bytes_per_file ({}) is too small to fit the header and one entry",
+                        max_bytes.unwrap_or_default()
+                    ),
+                ));
+            }
+            bytes += needed;
+            entries += 1;
+        }
+        if start < domains.len() {
+            current.push((url, &domains[start..]));
+        }
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
+
+    // Second pass: render, now that the number of parts is known
+    let count = parts.len();
+    Ok(parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, sections)| {
+            let domains: usize = sections.iter().map(|(_, d)| d.len()).sum();
+            let mut content = String::with_capacity(header.len() + domains * 35);
+            content.push_str(header);
+            content.push_str(&format!(
+                "# Part {} of {count}: {domains} domains\n#\n",
+                i + 1
+            ));
+            push_format_line(&mut content, ListFormat::Hosts, &prefix);
+            for (url, slice) in sections {
+                push_section(&mut content, url, slice, ListFormat::Hosts, &prefix);
+            }
+            content.push_str("\n# Domains in this part: ");
+            content.push_str(&domains.to_string());
+            content.push('\n');
+            HostsPart { content, domains }
+        })
+        .collect())
+}
+
+/// File name of part `index` (1-based) of a split output, e.g. hosts-2.txt
+/// for hosts.txt.
+pub(crate) fn part_file_name(file_name: &str, index: usize) -> String {
+    match file_name.rsplit_once('.') {
+        Some((stem, ext)) => format!("{stem}-{index}.{ext}"),
+        None => format!("{file_name}-{index}"),
+    }
+}
+
+/// The file that `name` is a part of, with the part's index: hosts.txt and
+/// 2 for hosts-2.txt. None if `name` isn't a part file name.
+pub(crate) fn split_part_file_name(name: &str) -> Option<(String, usize)> {
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) => (stem, Some(ext)),
+        None => (name, None),
+    };
+    let (base, index) = stem.rsplit_once('-')?;
+    let index = index.parse().ok()?;
+    let file_name = match ext {
+        Some(ext) => format!("{base}.{ext}"),
+        None => base.to_string(),
+    };
+    // Rejects "+2" and "02", which parse but aren't names part_file_name gives
+    (part_file_name(&file_name, index) == name).then_some((file_name, index))
+}
+
+/// File name of the index of a split output, e.g. hosts-index.txt for
+/// hosts.txt.
+pub(crate) fn index_file_name(file_name: &str) -> String {
+    match file_name.rsplit_once('.') {
+        Some((stem, ext)) => format!("{stem}-index.{ext}"),
+        None => format!("{file_name}-index"),
+    }
+}
+
+/// File name of the hosts list of one category, e.g. hosts-ads.txt.
+pub(crate) fn category_file_name(category: &str) -> String {
+    format!("hosts-{}.txt", category_stem(category))
+}
+
+/// A category as used in its file name: characters other than ASCII
+/// letters, digits, `-` and `_` become `-`, so a category can't point
+/// outside the output directory.
+fn category_stem(category: &str) -> String {
+    category
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// Checks that every category gets a hosts file of its own. `index` and
+/// numbers would be written over the index and parts of a split hosts.txt,
+/// `ads-index` or `ads-2` over those of category `ads`, and two categories
+/// that only differ in replaced characters, such as `ads/trackers` and
+/// `ads-trackers`, over each other. A build fails with this error in
+/// [`Converter::write`](crate::Converter::write); checking up front catches
+/// it before fetching anything.
+pub fn check_categories<'a>(categories: impl IntoIterator<Item = &'a str>) -> std::io::Result<()> {
+    let is_split_suffix =
+        |s: &str| s == "index" || (!s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()));
+    let conflict = |message: String| {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            message,
+        ))
+    };
+    let mut seen: Vec<(&str, String)> = Vec::new();
+    for category in categories {
+        if seen.iter().any(|(c, _)| *c == category) {
+            continue;
+        }
+        let stem = category_stem(category);
+        if is_split_suffix(&stem) {
+            return conflict(format!(
+                "category {category:?} would overwrite hosts-{stem}.txt of the split hosts.txt"
+            ));
+        }
+        for (other, other_stem) in &seen {
+            if stem == *other_stem {
+                return conflict(format!(
+                    "categories {other:?} and {category:?} would both be written to hosts-{stem}.txt"
+                ));
+            }
+            // hosts-ads-2.txt is both category "ads-2" and part 2 of "ads"
+            for (long, short) in [(&stem, other_stem), (other_stem, &stem)] {
+                if let Some(suffix) = long
+                    .strip_prefix(short.as_str())
+                    .and_then(|s| s.strip_prefix('-'))
+                    && is_split_suffix(suffix)
+                {
+                    return conflict(format!(
+                        "categories {other:?} and {category:?} would both write hosts-{long}.txt when split"
+                    ));
+                }
+            }
+        }
+        seen.push((category, stem));
+    }
+    Ok(())
+}
+
+/// Renders the index of a split output: the shared header, the part files
+/// with their domain counts as comments, then one part file name per line
+/// for scripts, and the usual `# Total unique domains:` trailer.
+pub(crate) fn render_index(header: &str, parts: &[(String, usize)], total_unique: usize) -> String {
+    let mut content = String::from(header);
+    content.push_str(&format!("# Parts: {}\n", parts.len()));
+    for (name, domains) in parts {
+        content.push_str(&format!("# - {name} --> {domains} domains\n"));
+    }
+    content.push_str("#\n\n");
+    for (name, _) in parts {
+        content.push_str(name);
+        content.push('\n');
+    }
+    content.push_str("\n# Total unique domains: ");
+    content.push_str(&total_unique.to_string());
+    content.push('\n');
+    content
+}
+
+/// Renders a RouterOS script adding one `/ip dns static` record per domain.
+///
+/// The script first removes every record tagged with the configured comment,
+/// so it can be re-imported after each build (`/import file-name=hosts.rsc`)
+/// and only ever touches the records it manages.
+pub(crate) fn render_rsc(
+    header: &str,
+    sources: &[SourceOutput],
+    total_unique: usize,
+    options: &RscOptions,
+    entry: &EntryFormat,
+) -> String {
+    let comment = quote_rsc(&options.comment);
+    let mut record = match (options.mode, entry) {
+        (RscMode::Nxdomain, _) => " type=NXDOMAIN".to_string(),
+        (RscMode::Address, EntryFormat::Sinkhole(ip @ IpAddr::V6(_))) => {
+            format!(" type=AAAA address={ip}")
+        }
+        (RscMode::Address, EntryFormat::Sinkhole(ip)) => format!(" address={ip}"),
+        // Domain-only hosts output has no address to reuse
+        (RscMode::Address, EntryFormat::DomainOnly) => " address=0.0.0.0".to_string(),
+    };
+    if options.match_subdomain {
+        record.push_str(" match-subdomain=yes");
+    }
+    record.push_str(" comment=");
+    record.push_str(&comment);
+    record.push('\n');
+
+    // Pre-allocate content buffer: header + "add name=" + record + avg domain
+    let mut content = String::with_capacity(header.len() + total_unique * (record.len() + 30));
+    content.push_str(header);
+    content.push_str("# Format: RouterOS script, import with /import file-name=<file>\n#\n\n");
+    content.push_str("/ip dns static remove [find comment=");
+    content.push_str(&comment);
+    content.push_str("]\n/ip dns static\n");
+
+    for SourceOutput { url, domains, .. } in sources {
+        content.push_str("\n# Source: ");
+        content.push_str(url);
+        content.push('\n');
+        for domain in domains {
+            content.push_str("add name=");
+            content.push_str(domain);
+            content.push_str(&record);
+        }
+    }
+
+    content.push_str("\n# Total unique domains: ");
+    content.push_str(&total_unique.to_string());
+    content.push('\n');
+    content
+}
+
+/// Quotes a string for a RouterOS script, escaping `"`, `\` and `$`.
+fn quote_rsc(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Destination of the files generated by a [`crate::Converter`], addressed by
+/// file name (`hosts.txt`, `hosts-2.txt`, `hosts.rsc`, ...).
+pub trait OutputSink {
+    /// Contents of a file written by a previous build, if any. Used for the
+    /// `max_change_percent` safety check.
+    fn read(&mut self, name: &str) -> impl Future<Output = Option<Vec<u8>>> + Send;
+
+    /// Replaces the file, so that readers see either the old or the new
+    /// content, and returns where it was written.
+    fn write(
+        &mut self,
+        name: &str,
+        content: &[u8],
+    ) -> impl Future<Output = std::io::Result<PathBuf>> + Send;
+
+    /// Removes a file left over from a previous build, returning false if
+    /// there was none.
+    fn remove(&mut self, name: &str) -> impl Future<Output = bool> + Send;
+
+    /// Writes a file kept next to the output but never published, such as
+    /// the build report. Same as [`write`](Self::write) by default.
+    fn write_private(
+        &mut self,
+        name: &str,
+        content: &[u8],
+    ) -> impl Future<Output = std::io::Result<PathBuf>> + Send {
+        self.write(name, content)
+    }
+
+    /// Called once a build has written and removed all of its files, so a
+    /// sink that publishes them can switch to the new set at once. Does
+    /// nothing by default.
+    fn commit(&mut self) -> impl Future<Output = std::io::Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// Writes the files into a directory, atomically. The directory must exist.
+#[derive(Debug, Clone)]
+pub struct DirectorySink {
+    dir: PathBuf,
+}
+
+impl DirectorySink {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        DirectorySink { dir: dir.into() }
+    }
+}
+
+impl OutputSink for DirectorySink {
+    async fn read(&mut self, name: &str) -> Option<Vec<u8>> {
+        tokio::fs::read(self.dir.join(name)).await.ok()
+    }
+
+    async fn write(&mut self, name: &str, content: &[u8]) -> std::io::Result<PathBuf> {
+        let path = self.dir.join(name);
+        write_atomic(&path, content).await.map_err(|e| {
+            std::io::Error::new(e.kind(), format!("failed to write {}: {e}", path.display()))
+        })?;
+        Ok(path)
+    }
+
+    async fn remove(&mut self, name: &str) -> bool {
+        tokio::fs::remove_file(self.dir.join(name)).await.is_ok()
+    }
+}
+
+/// Keeps the files in memory, e.g. to serve them without touching the disk.
+/// Files present before a build act as the previous build for the safety
+/// check.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink {
+    pub files: BTreeMap<String, Vec<u8>>,
+}
+
+impl OutputSink for MemorySink {
+    async fn read(&mut self, name: &str) -> Option<Vec<u8>> {
+        self.files.get(name).cloned()
+    }
+
+    async fn write(&mut self, name: &str, content: &[u8]) -> std::io::Result<PathBuf> {
+        self.files.insert(name.to_string(), content.to_vec());
+        Ok(PathBuf::from(name))
+    }
+
+    async fn remove(&mut self, name: &str) -> bool {
+        self.files.remove(name).is_some()
+    }
+}
+
+/// Writes `content` to `path` atomically: content is first written to a
+/// hidden temp file in the same directory, then moved into place with
+/// tokio::fs::rename() — an atomic rename on POSIX and Windows, same
+/// guarantee as Python's Path.replace() in the sibling project. This ensures
+/// readers of the file (RouterOS polling it over HTTP, or a concurrent
+/// process) never observe a partially-written file, even if this process is
+/// interrupted mid-write. On failure, the temp file is removed and the error
+/// is returned; the existing file is left untouched.
+pub(crate) async fn write_atomic(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let tmp_file_name = format!(
+        ".{}.tmp",
+        path.file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("hosts.txt")
+    );
+    let tmp_file = path.with_file_name(tmp_file_name);
+
+    if let Err(e) = tokio::fs::write(&tmp_file, content).await {
+        let _ = tokio::fs::remove_file(&tmp_file).await;
+        return Err(e);
+    }
+
+    if let Err(e) = tokio::fs::rename(&tmp_file, path).await {
+        let _ = tokio::fs::remove_file(&tmp_file).await;
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sources() -> Vec<SourceOutput> {
+        vec![SourceOutput {
+            url: "https://example.com/list.txt".to_string(),
+            domains: vec!["ads.example.com".to_string(), "tracker.net".to_string()],
+            ..Default::default()
+        }]
+    }
+
+    #[test]
+    fn test_render_hosts_parts_by_entries() {
+        let mut sources = sources();
+        sources.push(SourceOutput {
+            url: "https://example.com/other.txt".to_string(),
+            domains: vec!["one.org".to_string(), "two.org".to_string()],
+            ..Default::default()
+        });
+        let parts = render_hosts_parts(
+            "# header\n",
+            &sources,
+            None,
+            Some(3),
+            &EntryFormat::default(),
+        )
+        .unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].domains, 3);
+        assert_eq!(parts[1].domains, 1);
+        assert!(parts[0].content.contains("# Part 1 of 2: 3 domains"));
+        // The second source is split across both parts, with a section in each
+        assert!(
+            parts[0]
+                .content
+                .contains("# Source: https://example.com/other.txt\n\n0.0.0.0 one.org\n\n")
+        );
+        assert!(
+            parts[1]
+                .content
+                .contains("# Source: https://example.com/other.txt\n\n0.0.0.0 two.org\n\n")
+        );
+        assert!(
+            parts[1]
+                .content
+                .trim_end()
+                .ends_with("# Domains in this part: 1")
+        );
+    }
+
+    #[test]
+    fn test_render_hosts_parts_by_bytes() {
+        let domains: Vec<String> = (0..100).map(|i| format!("domain{i}.example.com")).collect();
+        let sources = vec![SourceOutput {
+            url: "list".to_string(),
+            domains,
+            ..Default::default()
+        }];
+        let max = 1_000;
+        let parts = render_hosts_parts(
+            "# header\n",
+            &sources,
+            Some(max),
+            None,
+            &EntryFormat::default(),
+        )
+        .unwrap();
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|p| p.content.len() <= max));
+        assert_eq!(parts.iter().map(|p| p.domains).sum::<usize>(), 100);
+
+        assert!(
+            render_hosts_parts(
+                "# header\n",
+                &sources,
+                Some(100),
+                None,
+                &EntryFormat::default()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_render_hosts_entry_format() {
+        let content = render_list(
+            "",
+            &sources(),
+            2,
+            ListFormat::Hosts,
+            &"ipv6".parse().unwrap(),
+        );
+        assert!(content.contains("# Format: :: domain.tld\n"));
+        assert!(content.contains("\n:: ads.example.com\n"));
+
+        let content = render_list(
+            "",
+            &sources(),
+            2,
+            ListFormat::Hosts,
+            &EntryFormat::DomainOnly,
+        );
+        assert!(content.contains("# Format: domain.tld\n"));
+        assert!(content.contains("\n\nads.example.com\ntracker.net\n"));
+    }
+
+    #[test]
+    fn test_compression_round_trip_and_limit() {
+        let content = b"0.0.0.0 example.com\n".repeat(100);
+        for compression in Compression::ALL {
+            let compressed = compression.compress(&content).unwrap();
+            assert_eq!(Compression::sniff(&compressed), Some(compression));
+            assert_eq!(
+                compression.decompress(&compressed, 10_000).unwrap(),
+                content
+            );
+            assert!(compression.decompress(&compressed, 100).is_err());
+        }
+        assert_eq!(Compression::sniff(&content), None);
+    }
+
+    #[test]
+    fn test_render_list_formats() {
+        let render =
+            |format| render_list("# header\n", &sources(), 2, format, &EntryFormat::default());
+
+        let dnsmasq = render(ListFormat::Dnsmasq);
+        assert!(dnsmasq.starts_with("# header\n# Format: address=/domain.tld/0.0.0.0\n"));
+        assert!(dnsmasq.contains("\naddress=/ads.example.com/0.0.0.0\n"));
+        let bare = render_list(
+            "",
+            &sources(),
+            2,
+            ListFormat::Dnsmasq,
+            &EntryFormat::DomainOnly,
+        );
+        assert!(bare.contains("\naddress=/tracker.net/\n"));
+
+        let unbound = render(ListFormat::Unbound);
+        assert!(unbound.contains("\nlocal-zone: \"tracker.net\" always_nxdomain\n"));
+
+        // Zone files comment with ';' and need SOA and NS records
+        let rpz = render(ListFormat::Rpz);
+        assert!(rpz.starts_with("; header\n; Format: domain.tld CNAME .\n"));
+        assert!(rpz.contains("\n$TTL 300\n@ IN SOA localhost. hostmaster.localhost. "));
+        assert!(rpz.contains("\nads.example.com CNAME .\n*.ads.example.com CNAME .\n"));
+        assert!(rpz.contains("\n; Source: "));
+        assert!(rpz.ends_with("; Total unique domains: 2\n"));
+        assert!(!rpz.lines().any(|line| line.starts_with('#')));
+
+        let adguard = render(ListFormat::Adguard);
+        assert!(adguard.starts_with("! header\n"));
+        assert!(adguard.contains("\n||ads.example.com^\n||tracker.net^\n"));
+    }
+
+    #[test]
+    fn test_render_rsc_address_uses_ipv6_sinkhole() {
+        let options = RscOptions {
+            mode: RscMode::Address,
+            ..Default::default()
+        };
+        let script = render_rsc("", &sources(), 2, &options, &"ipv6".parse().unwrap());
+        assert!(script.contains("add name=tracker.net type=AAAA address=:: match-subdomain=yes"));
+    }
+
+    #[test]
+    fn test_part_file_name() {
+        assert_eq!(part_file_name("hosts.txt", 2), "hosts-2.txt");
+        assert_eq!(part_file_name("hosts", 1), "hosts-1");
+    }
+
+    #[test]
+    fn test_split_part_file_name() {
+        assert_eq!(
+            split_part_file_name("hosts-2.txt"),
+            Some(("hosts.txt".to_string(), 2))
+        );
+        assert_eq!(
+            split_part_file_name("hosts-ads-10.txt"),
+            Some(("hosts-ads.txt".to_string(), 10))
+        );
+        assert_eq!(
+            split_part_file_name("hosts-1"),
+            Some(("hosts".to_string(), 1))
+        );
+        for name in [
+            "hosts.txt",
+            "hosts-ads.txt",
+            "hosts-index.txt",
+            "hosts-02.txt",
+            "hosts-+2.txt",
+        ] {
+            assert_eq!(split_part_file_name(name), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_check_categories() {
+        assert!(check_categories(["ads", "trackers", "ads", "ads-extra", "top10"]).is_ok());
+        for categories in [
+            vec!["index"],
+            vec!["ads", "2"],
+            vec!["ads/trackers", "ads-trackers"],
+            vec!["ads", "ads-index"],
+            vec!["ads-1", "ads"],
+        ] {
+            let err = check_categories(categories.clone()).unwrap_err();
+            assert_eq!(
+                err.kind(),
+                std::io::ErrorKind::InvalidInput,
+                "{categories:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_render_rsc_nxdomain() {
+        let script = render_rsc(
+            "# header\n",
+            &sources(),
+            2,
+            &RscOptions::default(),
+            &EntryFormat::default(),
+        );
+        assert!(script.starts_with("# header\n"));
+        assert!(script.contains(
+            "/ip dns static remove [find comment=\"adblock2mikrotik\"]\n/ip dns static\n"
+        ));
+        assert!(script.contains(
+            "add name=ads.example.com type=NXDOMAIN match-subdomain=yes comment=\"adblock2mikrotik\"\n"
+        ));
+        assert!(script.contains("add name=tracker.net type=NXDOMAIN"));
+        assert!(script.trim_end().ends_with("# Total unique domains: 2"));
+    }
+
+    #[test]
+    fn test_render_rsc_address_without_match_subdomain() {
+        let options = RscOptions {
+            mode: RscMode::Address,
+            match_subdomain: false,
+            comment: "my \"list\"".to_string(),
+            ..Default::default()
+        };
+        let script = render_rsc("", &sources(), 2, &options, &EntryFormat::default());
+        assert!(script.contains("remove [find comment=\"my \\\"list\\\"\"]"));
+        assert!(
+            script.contains("add name=tracker.net address=0.0.0.0 comment=\"my \\\"list\\\"\"\n")
+        );
+        assert!(!script.contains("match-subdomain"));
+    }
+}
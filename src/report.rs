@@ -0,0 +1,124 @@
+//! Machine-readable summary of a build.
+
+use crate::{DeployReport, SourceFormat};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// What [`crate::run_with_options`] did, for automation that would otherwise
+/// have to scrape the console output.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BuildReport {
+    /// RFC 3339 time the build started.
+    pub started_at: String,
+    pub elapsed_secs: f64,
+    /// One entry per configured source, in configuration order, including
+    /// sources that failed.
+    pub sources: Vec<SourceReport>,
+    /// Distinct `@@||domain^` exception rules across all sources.
+    pub exception_rules: usize,
+    /// Domains added by the local denylist.
+    pub denylisted: usize,
+    pub pruned_subdomains: usize,
+    /// Domains in the generated list.
+    pub total_unique: usize,
+    /// Files written, in the order they were written. Empty for a dry run
+    /// (but for a report written to [`crate::RunOptions::report_path`]) or
+    /// when nothing was converted.
+    pub files: Vec<PathBuf>,
+    pub dry_run: bool,
+    /// One entry per configured router, after a successful build.
+    pub routers: Vec<DeployReport>,
+    /// Why the build failed, if it did.
+    pub error: Option<String>,
+}
+
+/// Per-source part of a [`BuildReport`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SourceReport {
+    pub url: String,
+    pub name: Option<String>,
+    pub category: Option<String>,
+    /// Detected list format; None if the source could not be fetched.
+    pub format: Option<SourceFormat>,
+    /// Non-empty, non-comment lines downloaded.
+    pub fetched_lines: usize,
+    /// Domains this source contributed before exceptions, the allowlist and
+    /// subdomain pruning.
+    pub converted: usize,
+    /// Valid entries already contributed by an earlier source (or repeated).
+    pub duplicates: usize,
+    /// Lines that are not a supported rule, including `!` comments.
+    pub invalid: usize,
+    pub removed_by_exceptions: usize,
+    pub removed_by_allowlist: usize,
+    pub pruned_subdomains: usize,
+    /// Domains from this source in the generated list.
+    pub domains: usize,
+    pub fetch_secs: f64,
+    /// Age in seconds of the cached copy used when the live fetch failed.
+    pub stale_age_secs: Option<u64>,
+    /// Why the source could not be fetched, if it couldn't.
+    pub error: Option<String>,
+}
+
+/// File format for the build report written next to the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Json,
+}
+
+impl ReportFormat {
+    pub(crate) fn file_name(self) -> &'static str {
+        match self {
+            ReportFormat::Json => "build-report.json",
+        }
+    }
+
+    pub(crate) fn render(self, report: &BuildReport) -> std::io::Result<String> {
+        match self {
+            ReportFormat::Json => {
+                serde_json::to_string_pretty(report).map_err(std::io::Error::other)
+            }
+        }
+    }
+}
+
+impl FromStr for ReportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "json" => Ok(ReportFormat::Json),
+            _ => Err(anyhow::anyhow!(
+                "unsupported report format: {s} (expected json)"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_json() {
+        let report = BuildReport {
+            sources: vec![SourceReport {
+                url: "https://example.com/list.txt".to_string(),
+                format: Some(SourceFormat::Hosts),
+                converted: 2,
+                ..Default::default()
+            }],
+            total_unique: 2,
+            ..Default::default()
+        };
+        let json = ReportFormat::Json.render(&report).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["total_unique"], 2);
+        assert_eq!(value["sources"][0]["format"], "hosts");
+        assert_eq!(value["sources"][0]["error"], serde_json::Value::Null);
+        assert!("JSON".parse::<ReportFormat>().is_ok());
+        assert!("yaml".parse::<ReportFormat>().is_err());
+    }
+}
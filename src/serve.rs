@@ -0,0 +1,751 @@
+//! Built-in HTTP server publishing the generated files: a minimal HTTP/1.1
+//! responder on tokio's TcpListener, answering the plain `GET` RouterOS sends
+//! for one file.
+
+use crate::cache::fnv1a64;
+use crate::daemon::{reject_stdin, run_daemon};
+use crate::{
+    Compression, Converter, DirectorySink, OutputSink, RunOptions, Schedule, Source,
+    output::{index_file_name, part_file_name},
+    output_dir, print_logger,
+};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+
+/// Largest request head accepted; RouterOS sends well under 1 KiB.
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+/// How long a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One published file with its HTTP validators.
+struct ServedFile {
+    body: Vec<u8>,
+    etag: String,
+    last_modified: DateTime<Utc>,
+}
+
+/// The files currently published, shared between the build and the
+/// connections. A build's files are swapped in together on
+/// [`OutputSink::commit`], so a client downloads either the previous or the
+/// new build, never a mix such as a new index next to old parts.
+type Published = Arc<RwLock<HashMap<String, Arc<ServedFile>>>>;
+
+/// Sink that writes through to `inner` and publishes the files of a build
+/// once it commits.
+struct PublishingSink<S> {
+    inner: S,
+    published: Published,
+    /// Files written (Some) or removed (None) since the last commit.
+    pending: HashMap<String, Option<Arc<ServedFile>>>,
+}
+
+impl<S: OutputSink> PublishingSink<S> {
+    fn new(inner: S) -> Self {
+        PublishingSink {
+            inner,
+            published: Published::default(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Stages a file, published by the next [`apply`](Self::apply).
+    fn publish(&mut self, name: &str, body: &[u8]) {
+        // A compressed copy is written after its plain file and has the
+        // same content, so it shares that file's tag
+        let plain = Compression::ALL.into_iter().find_map(|c| {
+            let plain = name.strip_suffix(c.file_name("").as_str())?;
+            let file = self.pending.get(plain)?.as_ref()?;
+            Some(format!(
+                "{}-{}\"",
+                file.etag.strip_suffix('"')?,
+                c.encoding()
+            ))
+        });
+        let etag = plain.unwrap_or_else(|| content_etag(body));
+        // An unchanged file keeps its Last-Modified, so If-Modified-Since
+        // revalidation keeps answering 304
+        let last_modified = match self.published.read().unwrap().get(name) {
+            Some(previous) if previous.etag == etag => previous.last_modified,
+            _ => Utc::now(),
+        };
+        self.pending.insert(
+            name.to_string(),
+            Some(Arc::new(ServedFile {
+                body: body.to_vec(),
+                etag,
+                last_modified,
+            })),
+        );
+    }
+
+    /// Publishes everything staged since the last call in a single swap.
+    fn apply(&mut self) {
+        let mut published = self.published.write().unwrap();
+        for (name, file) in self.pending.drain() {
+            match file {
+                Some(file) => published.insert(name, file),
+                None => published.remove(&name),
+            };
+        }
+    }
+
+    /// Publishes the files a previous run left in `inner`, so they are
+    /// served even if the first build fails.
+    async fn preload(&mut self, converter: &Converter) {
+        let options = &converter.options;
+        let lists = converter.list_files();
+        let mut names = Vec::new();
+        for list in &lists {
+            names.push(list.clone());
+            names.push(index_file_name(list));
+        }
+        names.extend(options.rsc.as_ref().map(|rsc| rsc.file_name.clone()));
+        names.extend(options.formats.iter().map(|f| f.file_name.clone()));
+        for name in names {
+            self.preload_file(&name, &options.compression).await;
+        }
+        for list in &lists {
+            for i in 1.. {
+                if !self
+                    .preload_file(&part_file_name(list, i), &options.compression)
+                    .await
+                {
+                    break;
+                }
+            }
+        }
+        self.apply();
+    }
+
+    /// Publishes one file from `inner` and its `compression` copies,
+    /// returning false if the file isn't there.
+    async fn preload_file(&mut self, name: &str, compression: &[Compression]) -> bool {
+        let Some(content) = self.inner.read(name).await else {
+            return false;
+        };
+        self.publish(name, &content);
+        for compression in compression {
+            let name = compression.file_name(name);
+            if let Some(content) = self.inner.read(&name).await {
+                self.publish(&name, &content);
+            }
+        }
+        true
+    }
+}
+
+impl<S: OutputSink + Send> OutputSink for PublishingSink<S> {
+    async fn read(&mut self, name: &str) -> Option<Vec<u8>> {
+        self.inner.read(name).await
+    }
+
+    async fn write(&mut self, name: &str, content: &[u8]) -> std::io::Result<PathBuf> {
+        let path = self.inner.write(name, content).await?;
+        self.publish(name, content);
+        Ok(path)
+    }
+
+    /// Written to `inner` only: the build report names router URLs, local
+    /// paths and error details that have no business on an open port.
+    async fn write_private(&mut self, name: &str, content: &[u8]) -> std::io::Result<PathBuf> {
+        self.inner.write(name, content).await
+    }
+
+    async fn remove(&mut self, name: &str) -> bool {
+        let published = self.published.read().unwrap().contains_key(name);
+        self.pending.insert(name.to_string(), None);
+        self.inner.remove(name).await || published
+    }
+
+    async fn commit(&mut self) -> std::io::Result<()> {
+        self.inner.commit().await?;
+        self.apply();
+        Ok(())
+    }
+}
+
+/// A weak ETag for a generated file, hashed over its entries only: the
+/// header comments and the RPZ SOA record carry the build time, so hashing
+/// them would give every build a new tag even when no domain changed.
+fn content_etag(body: &[u8]) -> String {
+    let content: Vec<u8> = body
+        .split_inclusive(|&b| b == b'\n')
+        .filter(|line| {
+            !line.starts_with(b"#")
+                && !line.starts_with(b";")
+                && !line.starts_with(b"!")
+                && !line.starts_with(b"@ IN SOA ")
+        })
+        .flatten()
+        .copied()
+        .collect();
+    format!("W/\"{:016x}\"", fnv1a64(&content))
+}
+
+/// Like [`run_daemon`], and meanwhile serves every generated file over HTTP
+/// at `/<file name>` (e.g. `/hosts.txt`) on `listener`, until `shutdown`
+/// completes. The build report is only written to `sink`.
+///
+/// Files are written to `sink` as usual and served from memory with `ETag`,
+/// `Last-Modified` and `Content-Length`; conditional requests get a `304 Not
+/// Modified`. Clients accepting `zstd` or `gzip` get the compressed copy
+/// written with [`RunOptions::compression`], if there is one. A build's
+/// files are published together once it has written them all, and a failed
+/// build leaves the previous ones in place. Files already in `sink` from a
+/// previous run are served until the first build replaces them.
+///
+/// Fails before serving anything if a source is `-` (stdin), like
+/// [`run_daemon`].
+pub async fn serve<S, F>(
+    converter: &Converter,
+    sink: S,
+    listener: TcpListener,
+    schedule: &Schedule,
+    shutdown: F,
+) -> std::io::Result<()>
+where
+    S: OutputSink + Send,
+    F: Future<Output = ()>,
+{
+    reject_stdin(converter)?;
+    let mut sink = PublishingSink::new(sink);
+    sink.preload(converter).await;
+    let published = sink.published.clone();
+    if let Ok(addr) = listener.local_addr() {
+        converter
+            .console
+            .info(format_args!("Serving on http://{addr}/"));
+    }
+
+    // The accept loop never ends on its own; it is dropped, together with
+    // the connections in progress, once the daemon has shut down
+    tokio::select! {
+        result = run_daemon(converter, &mut sink, schedule, shutdown) => result,
+        () = accept_loop(converter, listener, published) => Ok(()),
+    }
+}
+
+async fn accept_loop(converter: &Converter, listener: TcpListener, published: Published) {
+    let mut connections = JoinSet::new();
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                connections.spawn(handle_connection(stream, published.clone()));
+            }
+            Err(e) => {
+                converter
+                    .console
+                    .warn(format_args!("failed to accept connection: {e}"));
+                // Typically out of file descriptors; don't spin on it
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+        // Reap finished connections so the set doesn't grow unbounded
+        while connections.try_join_next().is_some() {}
+    }
+}
+
+/// Answers a single request, then closes the connection.
+async fn handle_connection(mut stream: TcpStream, published: Published) {
+    let response = match tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut stream)).await {
+        Ok(Some(head)) => respond(&published, &head),
+        Ok(None) => response("400 Bad Request", &[], b"bad request\n", true),
+        // A client that never finishes its request gets no answer
+        Err(_) => return,
+    };
+    let _ = stream.write_all(&response).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Reads up to the blank line ending the request head. The request body, if
+/// any, is ignored.
+async fn read_head(stream: &mut TcpStream) -> Option<String> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+    loop {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            return String::from_utf8(buf[..end].to_vec()).ok();
+        }
+        if buf.len() > MAX_REQUEST_BYTES {
+            return None;
+        }
+    }
+}
+
+/// Builds the complete response to a request head.
+fn respond(published: &Published, head: &str) -> Vec<u8> {
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return response("400 Bad Request", &[], b"bad request\n", true);
+    };
+    let with_body = match method {
+        "GET" => true,
+        "HEAD" => false,
+        _ => {
+            return response(
+                "405 Method Not Allowed",
+                &[("Allow", "GET, HEAD".to_string())],
+                b"method not allowed\n",
+                true,
+            );
+        }
+    };
+    let header = |wanted: &str| {
+        head.lines().skip(1).find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case(wanted)
+                .then(|| value.trim())
+        })
+    };
+    let path = target.split(['?', '#']).next().unwrap_or_default();
+    let name = path.strip_prefix('/').unwrap_or(path);
+
+    // Files are looked up by name in memory, never on disk, so a path like
+    // `/../etc/passwd` simply isn't found
+    let (file, encoding, compressed) = {
+        let published = published.read().unwrap();
+        let Some(plain) = published.get(name) else {
+            return response("404 Not Found", &[], b"not found\n", with_body);
+        };
+        let variants: Vec<_> = Compression::ALL
+            .into_iter()
+            .filter_map(|c| Some((c, published.get(&c.file_name(name))?)))
+            .collect();
+        let accepted = header("Accept-Encoding");
+        match variants
+            .iter()
+            .find(|(c, _)| accepts_encoding(accepted, c.encoding()))
+        {
+            Some((c, file)) => (Arc::clone(file), Some(c.encoding()), true),
+            None => (Arc::clone(plain), None, !variants.is_empty()),
+        }
+    };
+    // If-None-Match takes precedence over If-Modified-Since (RFC 9110)
+    let not_modified = match (header("If-None-Match"), header("If-Modified-Since")) {
+        (Some(tags), _) => tags
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == file.etag.trim_start_matches("W/")),
+        (None, Some(since)) => DateTime::parse_from_rfc2822(since)
+            .is_ok_and(|since| file.last_modified.timestamp() <= since.timestamp()),
+        (None, None) => false,
+    };
+
+    let mut headers = vec![
+        ("ETag", file.etag.clone()),
+        ("Last-Modified", http_date(file.last_modified)),
+        ("Cache-Control", "no-cache".to_string()),
+        ("Content-Type", content_type(name).to_string()),
+    ];
+    if let Some(encoding) = encoding {
+        headers.push(("Content-Encoding", encoding.to_string()));
+    }
+    if compressed {
+        headers.push(("Vary", "Accept-Encoding".to_string()));
+    }
+    if not_modified {
+        response("304 Not Modified", &headers, &[], false)
+    } else {
+        response("200 OK", &headers, &file.body, with_body)
+    }
+}
+
+/// Serializes a response. `Content-Length` is always the length of `body`,
+/// also for HEAD requests (`with_body` false), which get no body. Every
+/// response closes the connection.
+fn response(status: &str, headers: &[(&str, String)], body: &[u8], with_body: bool) -> Vec<u8> {
+    let mut head = format!("HTTP/1.1 {status}\r\nConnection: close\r\n");
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    if !status.starts_with("304") {
+        if headers.is_empty() {
+            head.push_str("Content-Type: text/plain; charset=utf-8\r\n");
+        }
+        head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    head.push_str("\r\n");
+    let mut response = head.into_bytes();
+    if with_body {
+        response.extend_from_slice(body);
+    }
+    response
+}
+
+fn content_type(name: &str) -> &'static str {
+    if name.ends_with(".json") {
+        "application/json"
+    } else if name.ends_with(".gz") {
+        "application/gzip"
+    } else if name.ends_with(".zst") {
+        "application/zstd"
+    } else {
+        "text/plain; charset=utf-8"
+    }
+}
+
+/// Whether an `Accept-Encoding` header lists `encoding` without `q=0`.
+/// Only explicit entries count, not `*`.
+fn accepts_encoding(header: Option<&str>, encoding: &str) -> bool {
+    header.is_some_and(|value| {
+        value.split(',').any(|item| {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            name.eq_ignore_ascii_case(encoding)
+                && !params.any(|param| {
+                    param
+                        .trim()
+                        .strip_prefix("q=")
+                        .and_then(|q| q.trim().parse::<f32>().ok())
+                        == Some(0.0)
+                })
+        })
+    })
+}
+
+/// Formats a time as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// [`serve`] with the output directory and console output of
+/// [`crate::run_with_options`]. Fails if `addr` can't be bound or, like
+/// [`serve`], a source is `-` (stdin).
+pub async fn serve_with_options<I, F>(
+    sources: I,
+    options: &RunOptions,
+    schedule: &Schedule,
+    addr: SocketAddr,
+    shutdown: F,
+) -> std::io::Result<()>
+where
+    I: IntoIterator,
+    I::Item: Into<Source>,
+    F: Future<Output = ()>,
+{
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| std::io::Error::new(e.kind(), format!("failed to listen on {addr}: {e}")))?;
+    let converter = Converter::new()
+        .sources(sources)
+        .options(options.clone())
+        .logger(print_logger(options.verbosity));
+    let sink = DirectorySink::new(output_dir(options));
+    serve(&converter, sink, listener, schedule, shutdown).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemorySink;
+    use tokio::sync::Notify;
+
+    fn published(name: &str, body: &[u8]) -> Published {
+        let mut sink = PublishingSink::new(MemorySink::default());
+        sink.publish(name, body);
+        sink.apply();
+        sink.published
+    }
+
+    fn parse(response: &[u8]) -> (String, String) {
+        let text = String::from_utf8(response.to_vec()).unwrap();
+        let (head, body) = text.split_once("\r\n\r\n").unwrap();
+        (head.to_string(), body.to_string())
+    }
+
+    fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+        head.lines()
+            .find_map(|line| line.strip_prefix(&format!("{name}: ")))
+    }
+
+    #[test]
+    fn test_respond_get_head_and_not_found() {
+        let files = published("hosts.txt", b"0.0.0.0 example.com\n");
+
+        let (head, body) = parse(&respond(&files, "GET /hosts.txt HTTP/1.1\r\nHost: x"));
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert_eq!(body, "0.0.0.0 example.com\n");
+        assert_eq!(header(&head, "Content-Length"), Some("20"));
+        assert!(header(&head, "ETag").is_some());
+        assert!(header(&head, "Last-Modified").unwrap().ends_with(" GMT"));
+
+        let (head, body) = parse(&respond(&files, "HEAD /hosts.txt?x=1 HTTP/1.1"));
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert_eq!(header(&head, "Content-Length"), Some("20"));
+        assert!(body.is_empty());
+
+        let (head, _) = parse(&respond(&files, "GET /../hosts.txt HTTP/1.1"));
+        assert!(head.starts_with("HTTP/1.1 404"));
+        let (head, _) = parse(&respond(&files, "POST /hosts.txt HTTP/1.1"));
+        assert!(head.starts_with("HTTP/1.1 405"));
+        assert_eq!(header(&head, "Allow"), Some("GET, HEAD"));
+    }
+
+    #[test]
+    fn test_respond_conditional_requests() {
+        let files = published("hosts.txt", b"0.0.0.0 example.com\n");
+        let (head, _) = parse(&respond(&files, "GET /hosts.txt HTTP/1.1"));
+        let etag = header(&head, "ETag").unwrap();
+        let last_modified = header(&head, "Last-Modified").unwrap();
+
+        let request = format!("GET /hosts.txt HTTP/1.1\r\nif-none-match: W/{etag}");
+        let (head, body) = parse(&respond(&files, &request));
+        assert!(head.starts_with("HTTP/1.1 304"));
+        assert!(header(&head, "Content-Length").is_none());
+        assert!(body.is_empty());
+
+        let request = format!("GET /hosts.txt HTTP/1.1\r\nIf-Modified-Since: {last_modified}");
+        let (head, _) = parse(&respond(&files, &request));
+        assert!(head.starts_with("HTTP/1.1 304"));
+
+        // If-None-Match wins over a matching If-Modified-Since
+        let request = format!(
+            "GET /hosts.txt HTTP/1.1\r\nIf-None-Match: \"other\"\r\nIf-Modified-Since: {last_modified}"
+        );
+        let (head, _) = parse(&respond(&files, &request));
+        assert!(head.starts_with("HTTP/1.1 200"));
+
+        let request = "GET /hosts.txt HTTP/1.1\r\nIf-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT";
+        let (head, _) = parse(&respond(&files, request));
+        assert!(head.starts_with("HTTP/1.1 200"));
+    }
+
+    #[test]
+    fn test_respond_negotiates_precompressed_copy() {
+        let files = published("hosts.txt", b"0.0.0.0 example.com\n");
+        files.write().unwrap().insert(
+            "hosts.txt.gz".to_string(),
+            Arc::new(ServedFile {
+                body: b"gzipped".to_vec(),
+                etag: "\"gz\"".to_string(),
+                last_modified: Utc::now(),
+            }),
+        );
+
+        let request = "GET /hosts.txt HTTP/1.1\r\nAccept-Encoding: br, zstd;q=0, gzip;q=0.5";
+        let (head, body) = parse(&respond(&files, request));
+        assert_eq!(body, "gzipped");
+        assert_eq!(header(&head, "Content-Encoding"), Some("gzip"));
+        assert_eq!(
+            header(&head, "Content-Type"),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(header(&head, "ETag"), Some("\"gz\""));
+        assert_eq!(header(&head, "Vary"), Some("Accept-Encoding"));
+
+        for request in [
+            "GET /hosts.txt HTTP/1.1",
+            "GET /hosts.txt HTTP/1.1\r\nAccept-Encoding: gzip;q=0, *",
+        ] {
+            let (head, body) = parse(&respond(&files, request));
+            assert_eq!(body, "0.0.0.0 example.com\n");
+            assert!(header(&head, "Content-Encoding").is_none());
+            assert_eq!(header(&head, "Vary"), Some("Accept-Encoding"));
+        }
+
+        let (head, _) = parse(&respond(&files, "GET /hosts.txt.gz HTTP/1.1"));
+        assert_eq!(header(&head, "Content-Type"), Some("application/gzip"));
+        assert!(header(&head, "Content-Encoding").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_preload_publishes_previous_files() {
+        let mut inner = MemorySink::default();
+        for name in ["hosts-index.txt", "hosts-1.txt", "hosts-2.txt", "notes.txt"] {
+            inner.files.insert(name.to_string(), b"content\n".to_vec());
+        }
+        let mut sink = PublishingSink::new(inner);
+        sink.preload(&Converter::new()).await;
+        let mut names: Vec<String> = sink.published.read().unwrap().keys().cloned().collect();
+        names.sort();
+        // Only files a build would write are served
+        assert_eq!(names, ["hosts-1.txt", "hosts-2.txt", "hosts-index.txt"]);
+
+        assert!(sink.remove("hosts-2.txt").await);
+        assert!(sink.published.read().unwrap().contains_key("hosts-2.txt"));
+        sink.commit().await.unwrap();
+        assert!(!sink.published.read().unwrap().contains_key("hosts-2.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_build_is_published_on_commit() {
+        let mut sink = PublishingSink::new(MemorySink::default());
+        sink.write("hosts-index.txt", b"# Updated: 1\nhosts-1.txt\n")
+            .await
+            .unwrap();
+        sink.write("hosts-1.txt", b"0.0.0.0 example.com\n")
+            .await
+            .unwrap();
+        sink.write("hosts-1.txt.gz", b"gzipped").await.unwrap();
+        assert!(sink.published.read().unwrap().is_empty());
+        sink.commit().await.unwrap();
+        let published = sink.published.clone();
+        let etag = |name: &str| published.read().unwrap()[name].etag.clone();
+        assert_eq!(
+            etag("hosts-1.txt.gz"),
+            format!("{}-gzip\"", etag("hosts-1.txt").strip_suffix('"').unwrap())
+        );
+        let index = etag("hosts-index.txt");
+
+        // A rebuild with the same entries keeps the tag despite its new header
+        sink.write("hosts-index.txt", b"# Updated: 2\nhosts-1.txt\n")
+            .await
+            .unwrap();
+        sink.commit().await.unwrap();
+        assert_eq!(etag("hosts-index.txt"), index);
+        sink.write("hosts-index.txt", b"# Updated: 3\nhosts-2.txt\n")
+            .await
+            .unwrap();
+        sink.commit().await.unwrap();
+        assert_ne!(etag("hosts-index.txt"), index);
+    }
+
+    #[tokio::test]
+    async fn test_serve_publishes_each_build() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/rules")
+            .with_status(200)
+            .with_body("||example.com^\n")
+            .create_async()
+            .await;
+        let built = Arc::new(Notify::new());
+        let notify = built.clone();
+        let converter = Converter::new()
+            .source(format!("{}/rules", server.url()))
+            .options(RunOptions {
+                report: Some(crate::ReportFormat::Json),
+                ..Default::default()
+            })
+            .logger(move |_, message| {
+                if message.starts_with("Next build in") {
+                    notify.notify_one();
+                }
+            });
+        let mut sink = MemorySink::default();
+        for name in ["hosts.txt", "build-report.json"] {
+            sink.files
+                .insert(name.to_string(), b"previous build\n".to_vec());
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let url = format!("http://{addr}/hosts.txt");
+        let done = Notify::new();
+
+        let requests = async {
+            built.notified().await;
+            let client = reqwest::Client::new();
+            let response = client.get(&url).send().await.unwrap();
+            assert_eq!(response.status(), 200);
+            let etag = response.headers()["etag"].clone();
+            let body = response.text().await.unwrap();
+            assert!(body.contains("0.0.0.0 example.com\n"));
+
+            let response = client
+                .get(&url)
+                .header("If-None-Match", etag)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 304);
+
+            // The report stays private, from the previous run or this one
+            let report = format!("http://{addr}/build-report.json");
+            let response = client.get(&report).send().await.unwrap();
+            assert_eq!(response.status(), 404);
+            done.notify_one();
+        };
+        let schedule = Schedule::default();
+        let (served, ()) = tokio::join!(
+            serve(&converter, sink, listener, &schedule, done.notified()),
+            requests
+        );
+        served.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_deploy_fetches_the_new_build() {
+        let mut server = mockito::Server::new_async().await;
+        let _rules = server
+            .mock("GET", "/rules")
+            .with_status(200)
+            .with_body("||example.com^\n")
+            .create_async()
+            .await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // The router downloads the file from the server while the build is
+        // still deploying, like /tool/fetch does
+        let fetched = Arc::new(std::sync::Mutex::new(None));
+        let fetched_by_router = fetched.clone();
+        let _fetch = server
+            .mock("POST", "/rest/tool/fetch")
+            .with_body_from_request(move |_| {
+                use std::io::{Read, Write};
+                let mut stream = std::net::TcpStream::connect(addr).unwrap();
+                stream
+                    .write_all(b"GET /hosts.txt HTTP/1.1\r\nHost: x\r\n\r\n")
+                    .unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                *fetched_by_router.lock().unwrap() = Some(response);
+                b"[]".to_vec()
+            })
+            .create_async()
+            .await;
+        let _reload = server
+            .mock("POST", "/rest/ip/dns/adlist/reload")
+            .with_body("[]")
+            .create_async()
+            .await;
+        let router = crate::Router {
+            url: server.url(),
+            user: "api".to_string(),
+            password: "secret".to_string(),
+            action: crate::DeployAction::Upload,
+            files_url: Some(format!("http://{addr}")),
+            accept_invalid_certs: false,
+        };
+        let built = Arc::new(Notify::new());
+        let notify = built.clone();
+        let converter = Converter::new()
+            .source(format!("{}/rules", server.url()))
+            .options(RunOptions {
+                routers: vec![router],
+                ..Default::default()
+            })
+            .logger(move |_, message| {
+                if message.starts_with("Next build in") {
+                    notify.notify_one();
+                }
+            });
+        let schedule = Schedule::default();
+        serve(
+            &converter,
+            MemorySink::default(),
+            listener,
+            &schedule,
+            built.notified(),
+        )
+        .await
+        .unwrap();
+
+        let response = fetched.lock().unwrap().take().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.contains("0.0.0.0 example.com\n"));
+    }
+}
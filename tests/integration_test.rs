@@ -1,5 +1,10 @@
-use adblock2mikrotik_rust::{fetch_rules, run};
-use std::sync::OnceLock;
+use adblock2mikrotik_rust::{
+    CategoryOptions, Compression, Converter, DeployAction, FetchCache, FormatOutput, ListFormat,
+    LogLevel, MatchKind, MemorySink, Override, ReportFormat, Router, RscOptions, RunOptions,
+    Source, SourceFormat, fetch_rules, fetch_rules_cached, lookup, run, run_with_options,
+};
+use mockito::Matcher;
+use std::sync::{Arc, OnceLock};
 use tempfile::tempdir;
 use tokio::sync::Mutex;
 
@@ -42,6 +47,47 @@ async fn test_fetch_rules_success() {
     assert!(rules.contains(&"||test.com^".to_string()));
 }
 
+#[tokio::test]
+async fn test_fetch_rules_cached_sends_validators_and_reuses_body_on_304() {
+    let mut server = mockito::Server::new_async().await;
+
+    let first = server
+        .mock("GET", "/rules")
+        .match_header("if-none-match", Matcher::Missing)
+        .with_status(200)
+        .with_header("etag", "\"v1\"")
+        .with_header("last-modified", "Wed, 21 Oct 2026 07:28:00 GMT")
+        .with_body("||example.com^\n||test.com^\n")
+        .expect(1)
+        .create_async()
+        .await;
+    let revalidated = server
+        .mock("GET", "/rules")
+        .match_header("if-none-match", "\"v1\"")
+        .match_header("if-modified-since", "Wed, 21 Oct 2026 07:28:00 GMT")
+        .with_status(304)
+        .expect(1)
+        .create_async()
+        .await;
+
+    let url = format!("{}/rules", server.url());
+    let client = reqwest::Client::new();
+    let dir = tempdir().unwrap();
+    let cache = FetchCache::new(dir.path());
+
+    let fresh = fetch_rules_cached(&client, &url, Some(&cache))
+        .await
+        .expect("first fetch");
+    let cached = fetch_rules_cached(&client, &url, Some(&cache))
+        .await
+        .expect("revalidated fetch");
+
+    assert_eq!(fresh, vec!["||example.com^", "||test.com^"]);
+    assert_eq!(cached, fresh, "304 must reuse the cached body");
+    first.assert_async().await;
+    revalidated.assert_async().await;
+}
+
 #[tokio::test(start_paused = true)]
 async fn test_fetch_rules_http_error() {
     // start_paused = true: tokio mock-time advances automatically when all tasks
@@ -184,6 +230,901 @@ async fn test_run_writes_expected_hosts_file_format() {
     );
 }
 
+#[tokio::test]
+async fn test_run_applies_exception_rules_across_sources() {
+    // Exceptions from one source must cancel blocks from every source, both
+    // for the exact domain and for its subdomains.
+    let _guard = output_dir_lock().lock().await;
+
+    let mut server1 = mockito::Server::new_async().await;
+    let mut server2 = mockito::Server::new_async().await;
+    let _m1 = server1
+        .mock("GET", "/block")
+        .with_status(200)
+        .with_body(
+            "||example.com^\n\
+             ||ads.example.com^\n\
+             ||keep.com^\n\
+             ||allowed.org^\n",
+        )
+        .create_async()
+        .await;
+    let _m2 = server2
+        .mock("GET", "/allow")
+        .with_status(200)
+        .with_body("@@||example.com^\n@@||allowed.org^$important\n")
+        .create_async()
+        .await;
+
+    let url1 = format!("{}/block", server1.url());
+    let url2 = format!("{}/allow", server2.url());
+    let temp_dir = tempdir().unwrap();
+
+    // SAFETY: guarded by output_dir_lock() above.
+    unsafe { std::env::set_var("OUTPUT_DIR", temp_dir.path()) };
+    let result = run(vec![&url1, &url2]).await;
+    unsafe { std::env::remove_var("OUTPUT_DIR") };
+
+    assert!(result.is_ok());
+
+    let content = std::fs::read_to_string(temp_dir.path().join("hosts.txt")).unwrap();
+    assert!(content.contains("0.0.0.0 keep.com"));
+    assert!(!content.contains("0.0.0.0 example.com"));
+    assert!(!content.contains("0.0.0.0 ads.example.com"));
+    assert!(!content.contains("0.0.0.0 allowed.org"));
+    assert!(content.contains("block --> 1 unique domains (3 removed by exceptions)"));
+    assert!(content.contains("# Total unique domains: 1"));
+}
+
+#[tokio::test]
+async fn test_run_normalizes_domain_case() {
+    // Lists differ in case: duplicates, exceptions and pruning must all see
+    // through it, and the output is lowercase.
+    let _guard = output_dir_lock().lock().await;
+
+    let mut server = mockito::Server::new_async().await;
+    let _m1 = server
+        .mock("GET", "/block")
+        .with_status(200)
+        .with_body("||Example.com^\n||ads.EXAMPLE.com^\n||example.com^\n||Tracker.net^\n")
+        .create_async()
+        .await;
+    let _m2 = server
+        .mock("GET", "/allow")
+        .with_status(200)
+        .with_body("@@||tracker.net^\n")
+        .create_async()
+        .await;
+    let url1 = format!("{}/block", server.url());
+    let url2 = format!("{}/allow", server.url());
+    let temp_dir = tempdir().unwrap();
+
+    // SAFETY: guarded by output_dir_lock() above.
+    unsafe { std::env::set_var("OUTPUT_DIR", temp_dir.path()) };
+    let result = run(vec![&url1, &url2]).await;
+    unsafe { std::env::remove_var("OUTPUT_DIR") };
+
+    assert!(result.is_ok());
+
+    let content = std::fs::read_to_string(temp_dir.path().join("hosts.txt")).unwrap();
+    assert!(content.contains("0.0.0.0 example.com"));
+    assert!(!content.contains("Example.com"));
+    assert!(!content.to_lowercase().contains("ads.example.com"));
+    assert!(!content.to_lowercase().contains("tracker.net"));
+    assert!(content.contains("# Total unique domains: 1"));
+}
+
+#[tokio::test]
+async fn test_run_applies_local_allowlist_and_denylist() {
+    let _guard = output_dir_lock().lock().await;
+
+    let mut server = mockito::Server::new_async().await;
+    let _m = server
+        .mock("GET", "/rules")
+        .with_status(200)
+        .with_body("||example.com^\n||cdn.saas.com^\n||already.com^\n@@||phish.net^\n")
+        .create_async()
+        .await;
+    let url = format!("{}/rules", server.url());
+    let temp_dir = tempdir().unwrap();
+
+    let options = RunOptions {
+        allowlist: vec!["saas.com # broken SaaS".to_string()],
+        denylist: vec![
+            "phish.net".to_string(),
+            "already.com".to_string(),
+            "not a domain".to_string(),
+        ],
+        ..Default::default()
+    };
+
+    // SAFETY: guarded by output_dir_lock() above.
+    unsafe { std::env::set_var("OUTPUT_DIR", temp_dir.path()) };
+    let result = run_with_options(vec![&url], &options).await;
+    unsafe { std::env::remove_var("OUTPUT_DIR") };
+
+    assert!(result.is_ok());
+
+    let content = std::fs::read_to_string(temp_dir.path().join("hosts.txt")).unwrap();
+    assert!(
+        !content.contains("cdn.saas.com"),
+        "allowlist covers subdomains"
+    );
+    assert!(content.contains("rules --> 2 unique domains (1 removed by allowlist)"));
+    // Denylisted domain survives an upstream exception, in its own section;
+    // one already blocked upstream is not repeated.
+    assert!(content.contains("# Source: local denylist\n\n0.0.0.0 phish.net\n"));
+    assert_eq!(content.matches("0.0.0.0 already.com").count(), 1);
+    assert!(content.contains("# - local denylist --> 1 unique domains"));
+    assert!(content.contains("# Total unique domains: 3"));
+}
+
+#[tokio::test]
+async fn test_run_merges_hosts_and_domain_list_sources() {
+    let _guard = output_dir_lock().lock().await;
+
+    let mut server = mockito::Server::new_async().await;
+    let _m1 = server
+        .mock("GET", "/hosts")
+        .with_status(200)
+        .with_body(
+            "# StevenBlack-style hosts file\n\
+             127.0.0.1 localhost\n\
+             127.0.0.1 localhost.localdomain\n\
+             0.0.0.0 ads.example.com\n\
+             0.0.0.0 tracker.example.net # comment\n",
+        )
+        .create_async()
+        .await;
+    let _m2 = server
+        .mock("GET", "/domains")
+        .with_status(200)
+        .with_body("ads.example.com\nphish.example.org\ninvalid_domain\n")
+        .create_async()
+        .await;
+
+    let hosts_url = format!("{}/hosts", server.url());
+    let domains_url = format!("{}/domains", server.url());
+    let temp_dir = tempdir().unwrap();
+
+    // SAFETY: guarded by output_dir_lock() above.
+    unsafe { std::env::set_var("OUTPUT_DIR", temp_dir.path()) };
+    let result = run(vec![&hosts_url, &domains_url]).await;
+    unsafe { std::env::remove_var("OUTPUT_DIR") };
+
+    assert!(result.is_ok());
+
+    let content = std::fs::read_to_string(temp_dir.path().join("hosts.txt")).unwrap();
+    assert!(content.contains("0.0.0.0 ads.example.com"));
+    assert!(content.contains("0.0.0.0 tracker.example.net"));
+    assert!(content.contains("0.0.0.0 phish.example.org"));
+    assert!(!content.contains("localhost"));
+    assert!(!content.contains("invalid_domain"));
+    assert!(content.contains("hosts --> 2 unique domains"));
+    assert!(content.contains("domains --> 1 unique domains"));
+}
+
+#[tokio::test]
+async fn test_run_prunes_subdomains_covered_across_sources() {
+    // The parent arrives from a later source than its subdomains: pruning
+    // must still drop them, and count them against the source they came from.
+    let _guard = output_dir_lock().lock().await;
+
+    let mut server = mockito::Server::new_async().await;
+    let _m1 = server
+        .mock("GET", "/first")
+        .with_status(200)
+        .with_body("||ads.example.com^\n||a.b.example.com^\n||other.com^\n||badexample.com^\n")
+        .create_async()
+        .await;
+    let _m2 = server
+        .mock("GET", "/second")
+        .with_status(200)
+        .with_body("||example.com^\n||cdn.other.com^\n")
+        .create_async()
+        .await;
+    let url1 = format!("{}/first", server.url());
+    let url2 = format!("{}/second", server.url());
+
+    let pruned_dir = tempdir().unwrap();
+    let full_dir = tempdir().unwrap();
+
+    // SAFETY: guarded by output_dir_lock() above.
+    unsafe { std::env::set_var("OUTPUT_DIR", pruned_dir.path()) };
+    let pruned = run(vec![&url1, &url2]).await;
+    unsafe { std::env::set_var("OUTPUT_DIR", full_dir.path()) };
+    let options = RunOptions {
+        prune_subdomains: false,
+        ..Default::default()
+    };
+    let full = run_with_options(vec![&url1, &url2], &options).await;
+    unsafe { std::env::remove_var("OUTPUT_DIR") };
+
+    assert!(pruned.is_ok() && full.is_ok());
+
+    let content = std::fs::read_to_string(pruned_dir.path().join("hosts.txt")).unwrap();
+    assert!(!content.contains("ads.example.com"));
+    assert!(!content.contains("a.b.example.com"));
+    assert!(!content.contains("cdn.other.com"));
+    assert!(content.contains("0.0.0.0 badexample.com"));
+    assert!(content.contains("first --> 2 unique domains (2 redundant subdomains pruned)"));
+    assert!(content.contains("second --> 1 unique domains (1 redundant subdomains pruned)"));
+    assert!(content.contains("# Redundant subdomains pruned: 3"));
+    assert!(content.contains("# Total unique domains: 3"));
+
+    let content = std::fs::read_to_string(full_dir.path().join("hosts.txt")).unwrap();
+    assert!(content.contains("0.0.0.0 ads.example.com"));
+    assert!(content.contains("# Total unique domains: 6"));
+}
+
+#[tokio::test]
+async fn test_run_writes_rsc_script_alongside_hosts_file() {
+    let _guard = output_dir_lock().lock().await;
+
+    let mut server = mockito::Server::new_async().await;
+    let _m = server
+        .mock("GET", "/rules")
+        .with_status(200)
+        .with_body("||example.com^\n||test.com^\n")
+        .create_async()
+        .await;
+    let url = format!("{}/rules", server.url());
+    let temp_dir = tempdir().unwrap();
+
+    let options = RunOptions {
+        rsc: Some(RscOptions::default()),
+        ..Default::default()
+    };
+
+    // SAFETY: guarded by output_dir_lock() above.
+    unsafe { std::env::set_var("OUTPUT_DIR", temp_dir.path()) };
+    let result = run_with_options(vec![&url], &options).await;
+    unsafe { std::env::remove_var("OUTPUT_DIR") };
+
+    assert!(result.is_ok());
+    assert!(temp_dir.path().join("hosts.txt").exists());
+
+    let script = std::fs::read_to_string(temp_dir.path().join("hosts.rsc")).unwrap();
+    assert!(script.contains("# Last modified:"));
+    assert!(script.contains("/ip dns static remove [find comment=\"adblock2mikrotik\"]"));
+    assert!(script.contains(
+        "add name=example.com type=NXDOMAIN match-subdomain=yes comment=\"adblock2mikrotik\""
+    ));
+    assert!(script.contains("add name=test.com "));
+    assert!(!script.contains("0.0.0.0 example.com"));
+}
+
+#[tokio::test]
+async fn test_run_writes_other_resolver_formats() {
+    let mut server = mockito::Server::new_async().await;
+    let _m = server
+        .mock("GET", "/rules")
+        .with_status(200)
+        .with_body("||example.com^\n||test.com^\n")
+        .create_async()
+        .await;
+    let converter = Converter::new()
+        .source(format!("{}/rules", server.url()))
+        .options(RunOptions {
+            formats: vec![
+                ListFormat::Dnsmasq.into(),
+                ListFormat::Unbound.into(),
+                ListFormat::Rpz.into(),
+                FormatOutput {
+                    format: ListFormat::Adguard,
+                    file_name: "filter.txt".to_string(),
+                },
+            ],
+            ..Default::default()
+        });
+    let mut sink = MemorySink::default();
+    let report = converter.run(&mut sink).await.unwrap();
+    assert_eq!(report.files.len(), 5);
+    let file = |name: &str| String::from_utf8(sink.files[name].clone()).unwrap();
+
+    assert!(file("hosts.txt").contains("\n0.0.0.0 example.com\n"));
+    assert!(file("dnsmasq.conf").contains("\naddress=/example.com/0.0.0.0\n"));
+    assert!(file("unbound.conf").contains("\nlocal-zone: \"test.com\" always_nxdomain\n"));
+    let rpz = file("rpz.zone");
+    assert!(rpz.starts_with("; Title: "));
+    assert!(rpz.contains(" IN SOA "));
+    assert!(rpz.contains("\n*.test.com CNAME .\n"));
+    let adguard = file("filter.txt");
+    assert!(adguard.contains("! Total unique domains: 2\n"));
+    assert!(adguard.contains("\n||example.com^\n"));
+}
+
+#[tokio::test]
+async fn test_run_splits_output_into_parts_with_index() {
+    let _guard = output_dir_lock().lock().await;
+
+    let mut server = mockito::Server::new_async().await;
+    let _m = server
+        .mock("GET", "/rules")
+        .with_status(200)
+        .with_body("||a.com^\n||b.com^\n||c.com^\n||d.com^\n||e.com^\n")
+        .create_async()
+        .await;
+    let url = format!("{}/rules", server.url());
+    let temp_dir = tempdir().unwrap();
+    // Left over from an earlier build that needed more parts, and from one
+    // that wasn't split
+    std::fs::write(temp_dir.path().join("hosts-4.txt"), "stale").unwrap();
+    std::fs::write(temp_dir.path().join("hosts.txt"), "stale").unwrap();
+
+    let options = RunOptions {
+        max_entries_per_file: Some(2),
+        ..Default::default()
+    };
+
+    // SAFETY: guarded by output_dir_lock() above.
+    unsafe { std::env::set_var("OUTPUT_DIR", temp_dir.path()) };
+    let result = run_with_options(vec![&url], &options).await;
+    unsafe { std::env::remove_var("OUTPUT_DIR") };
+
+    assert!(result.is_ok());
+    assert!(!temp_dir.path().join("hosts.txt").exists());
+    assert!(!temp_dir.path().join("hosts-4.txt").exists());
+
+    let part1 = std::fs::read_to_string(temp_dir.path().join("hosts-1.txt")).unwrap();
+    let part3 = std::fs::read_to_string(temp_dir.path().join("hosts-3.txt")).unwrap();
+    assert!(part1.contains("# Title: Unified DNS blocklist"));
+    assert!(part1.contains("# Part 1 of 3: 2 domains"));
+    assert!(part1.contains("0.0.0.0 a.com\n0.0.0.0 b.com\n"));
+    assert!(part3.contains("# Part 3 of 3: 1 domains"));
+    assert!(part3.contains("0.0.0.0 e.com"));
+
+    let index = std::fs::read_to_string(temp_dir.path().join("hosts-index.txt")).unwrap();
+    assert!(index.contains("\nhosts-1.txt\nhosts-2.txt\nhosts-3.txt\n"));
+    assert!(index.trim_end().ends_with("# Total unique domains: 5"));
+
+    // Turning splitting off removes the parts and the index
+    unsafe { std::env::set_var("OUTPUT_DIR", temp_dir.path()) };
+    let result = run_with_options(vec![&url], &RunOptions::default()).await;
+    unsafe { std::env::remove_var("OUTPUT_DIR") };
+
+    assert!(result.is_ok());
+    assert!(temp_dir.path().join("hosts.txt").exists());
+    assert!(!temp_dir.path().join("hosts-index.txt").exists());
+    assert!(!temp_dir.path().join("hosts-1.txt").exists());
+    assert!(!temp_dir.path().join("hosts-3.txt").exists());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_run_falls_back_to_cached_copy_when_fetch_fails() {
+    let _guard = output_dir_lock().lock().await;
+
+    let mut server = mockito::Server::new_async().await;
+    let ok = server
+        .mock("GET", "/rules")
+        .with_status(200)
+        .with_body("||example.com^\n||test.com^\n")
+        .create_async()
+        .await;
+    let url = format!("{}/rules", server.url());
+    let output_dir = tempdir().unwrap();
+    let cache_dir = tempdir().unwrap();
+    let options = RunOptions {
+        cache_dir: Some(cache_dir.path().to_path_buf()),
+        ..Default::default()
+    };
+
+    // SAFETY: guarded by output_dir_lock() above.
+    unsafe { std::env::set_var("OUTPUT_DIR", output_dir.path()) };
+    let first = run_with_options(vec![&url], &options).await;
+
+    // Upstream now fails on every retry attempt
+    ok.remove_async().await;
+    let _failing = server
+        .mock("GET", "/rules")
+        .with_status(500)
+        .expect(3)
+        .create_async()
+        .await;
+    let second = run_with_options(vec![&url], &options).await;
+
+    unsafe { std::env::remove_var("OUTPUT_DIR") };
+
+    assert!(first.is_ok() && second.is_ok());
+
+    let content = std::fs::read_to_string(output_dir.path().join("hosts.txt")).unwrap();
+    assert!(content.contains("0.0.0.0 example.com"));
+    assert!(content.contains("0.0.0.0 test.com"));
+    assert!(content.contains("rules --> 2 unique domains (stale: cached copy from "));
+}
+
+#[tokio::test]
+async fn test_run_refuses_suspicious_drop_unless_forced() {
+    let _guard = output_dir_lock().lock().await;
+
+    let mut server = mockito::Server::new_async().await;
+    let _m = server
+        .mock("GET", "/rules")
+        .with_status(200)
+        .with_body("||example.com^\n||test.com^\n")
+        .create_async()
+        .await;
+    let url = format!("{}/rules", server.url());
+    let temp_dir = tempdir().unwrap();
+    let hosts = temp_dir.path().join("hosts.txt");
+    let previous = "# Title: previous build\n0.0.0.0 a.com\n\n# Total unique domains: 300000\n";
+    std::fs::write(&hosts, previous).unwrap();
+
+    let mut options = RunOptions {
+        max_change_percent: Some(50.0),
+        ..Default::default()
+    };
+
+    // SAFETY: guarded by output_dir_lock() above.
+    unsafe { std::env::set_var("OUTPUT_DIR", temp_dir.path()) };
+    let refused = run_with_options(vec![&url], &options).await;
+    let unchanged = std::fs::read_to_string(&hosts).unwrap();
+    options.force = true;
+    let forced = run_with_options(vec![&url], &options).await;
+    unsafe { std::env::remove_var("OUTPUT_DIR") };
+
+    assert!(refused.is_err(), "a 300,000 -> 2 drop must abort the build");
+    assert_eq!(unchanged, previous, "existing hosts.txt must be kept");
+    assert!(forced.is_ok());
+    let content = std::fs::read_to_string(&hosts).unwrap();
+    assert!(content.trim_end().ends_with("# Total unique domains: 2"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_dry_run_writes_nothing_and_fails_on_unreachable_source() {
+    // output_dir takes precedence over OUTPUT_DIR, so no env lock is needed
+    let mut server = mockito::Server::new_async().await;
+    let _m = server
+        .mock("GET", "/rules")
+        .with_status(200)
+        .with_body("||example.com^\n")
+        .create_async()
+        .await;
+    let _missing = server
+        .mock("GET", "/missing")
+        .with_status(404)
+        .expect(3)
+        .create_async()
+        .await;
+    let url = format!("{}/rules", server.url());
+    let missing = format!("{}/missing", server.url());
+    let temp_dir = tempdir().unwrap();
+    let mut options = RunOptions {
+        output_dir: Some(temp_dir.path().to_path_buf()),
+        dry_run: true,
+        report: Some(ReportFormat::Json),
+        ..Default::default()
+    };
+
+    let passed = run_with_options(vec![&url], &options).await;
+    let failed = run_with_options(vec![&url, &missing], &options).await;
+
+    assert!(passed.is_ok());
+    assert!(failed.is_err(), "an unreachable source must fail the check");
+    assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+
+    // Only a report given a path of its own is written
+    let report_dir = tempdir().unwrap();
+    let report_path = report_dir.path().join("check.json");
+    options.report_path = Some(report_path.clone());
+    let report = run_with_options(vec![&url], &options).await.unwrap();
+    assert_eq!(report.files, vec![report_path.clone()]);
+    assert!(
+        std::fs::read_to_string(&report_path)
+            .unwrap()
+            .contains("\"dry_run\": true")
+    );
+    assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_run_returns_build_report_and_writes_json() {
+    let mut server = mockito::Server::new_async().await;
+    let _a = server
+        .mock("GET", "/a")
+        .with_status(200)
+        .with_body("! comment\n||example.com^\n||example.com^\n||test.com^\n/ads/*\n")
+        .create_async()
+        .await;
+    let _missing = server
+        .mock("GET", "/missing")
+        .with_status(500)
+        .expect(3)
+        .create_async()
+        .await;
+    let a = format!("{}/a", server.url());
+    let missing = format!("{}/missing", server.url());
+    let temp_dir = tempdir().unwrap();
+    let options = RunOptions {
+        output_dir: Some(temp_dir.path().to_path_buf()),
+        report: Some(ReportFormat::Json),
+        ..Default::default()
+    };
+
+    let report = run_with_options(vec![&a, &missing], &options)
+        .await
+        .unwrap();
+
+    assert_eq!(report.total_unique, 2);
+    assert_eq!(report.sources.len(), 2);
+    let source = &report.sources[0];
+    assert_eq!(source.fetched_lines, 5);
+    assert_eq!(
+        (source.converted, source.duplicates, source.invalid),
+        (2, 1, 2)
+    );
+    assert!(source.error.is_none());
+    assert!(report.sources[1].error.is_some());
+    let json_path = temp_dir.path().join("build-report.json");
+    assert_eq!(
+        report.files,
+        vec![temp_dir.path().join("hosts.txt"), json_path.clone()]
+    );
+
+    let json = std::fs::read_to_string(json_path).unwrap();
+    assert!(json.contains("\"total_unique\": 2"));
+    assert!(json.contains(&format!("\"url\": \"{missing}\"")));
+}
+
+#[tokio::test]
+async fn test_converter_in_memory_with_logger() {
+    let mut server = mockito::Server::new_async().await;
+    let _m = server
+        .mock("GET", "/rules")
+        .with_status(200)
+        .with_body("||example.com^\n||ads.example.com^\n||test.com^\n")
+        .create_async()
+        .await;
+    let url = format!("{}/rules", server.url());
+    let messages = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let log = messages.clone();
+    let converter = Converter::new()
+        .source(&url)
+        .client(reqwest::Client::new())
+        .options(RunOptions {
+            allowlist: vec!["api.test.com".to_string()],
+            denylist: vec!["local.example.org".to_string(), "bad entry".to_string()],
+            max_change_percent: Some(50.0),
+            ..Default::default()
+        })
+        .logger(move |level, message| log.lock().unwrap().push((level, message.to_string())));
+
+    let conversion = converter.convert().await;
+    assert_eq!(conversion.domains.len(), 3);
+    assert!(conversion.domains.contains("local.example.org"));
+    assert!(!conversion.domains.contains("ads.example.com"));
+    let sections: Vec<(&str, &[String])> = conversion.sections().collect();
+    assert_eq!(sections.len(), 2);
+    assert_eq!(sections[0].0, url);
+    assert_eq!(sections[1].0, "local denylist");
+
+    let mut sink = MemorySink::default();
+    let report = converter.run(&mut sink).await.unwrap();
+    assert_eq!(report.files, vec![std::path::PathBuf::from("hosts.txt")]);
+    let hosts = String::from_utf8(sink.files["hosts.txt"].clone()).unwrap();
+    assert!(hosts.contains("\n0.0.0.0 test.com\n"));
+
+    // The files already in the sink are the previous build for the safety check
+    sink.files.insert(
+        "hosts.txt".to_string(),
+        b"# Total unique domains: 300000\n".to_vec(),
+    );
+    assert!(converter.run(&mut sink).await.is_err());
+    // So is the index of a split build, when splitting was just turned off
+    let previous = sink.files.remove("hosts.txt").unwrap();
+    sink.files.insert("hosts-index.txt".to_string(), previous);
+    assert!(converter.run(&mut sink).await.is_err());
+    assert!(!sink.files.contains_key("hosts.txt"));
+
+    let messages = messages.lock().unwrap();
+    assert!(
+        messages
+            .iter()
+            .any(|(level, message)| *level == LogLevel::Warning
+                && message.contains("ignoring invalid denylist entry: bad entry"))
+    );
+    assert!(messages.iter().any(|(level, message)| *level == LogLevel::Warning
+        && message
+            == "allowlisted api.test.com is still blocked through its parent domain test.com"));
+    assert!(
+        messages
+            .iter()
+            .any(|(level, message)| *level == LogLevel::Info
+                && message.starts_with("Converted 3 unique domains"))
+    );
+}
+
+#[tokio::test]
+async fn test_deploy_uploads_and_reloads_after_build() {
+    let mut server = mockito::Server::new_async().await;
+    let _rules = server
+        .mock("GET", "/rules")
+        .with_status(200)
+        .with_body("||example.com^\n")
+        .create_async()
+        .await;
+    // "api:secret" in base64
+    let auth = "Basic YXBpOnNlY3JldA==";
+    let upload = server
+        .mock("POST", "/rest/tool/fetch")
+        .match_header("authorization", auth)
+        .match_body(Matcher::Json(serde_json::json!({
+            "url": "http://192.168.88.10:8080/lists/hosts.txt",
+            "dst-path": "hosts.txt",
+        })))
+        .with_body("[]")
+        .create_async()
+        .await;
+    // hosts-1.txt and hosts-2.txt are left from a build that was split
+    let _files = server
+        .mock("GET", "/rest/file")
+        .match_header("authorization", auth)
+        .with_body(
+            serde_json::json!([
+                { ".id": "*1", "name": "hosts.txt", "type": ".txt file" },
+                { ".id": "*2", "name": "hosts-1.txt", "type": ".txt file" },
+                { ".id": "*3", "name": "hosts-2.txt", "type": ".txt file" },
+                { ".id": "*4", "name": "hosts-ads.txt", "type": ".txt file" },
+                { ".id": "*5", "name": "flash", "type": "disk" },
+            ])
+            .to_string(),
+        )
+        .create_async()
+        .await;
+    let stale_parts = server
+        .mock("DELETE", Matcher::Regex(r"^/rest/file/\*[23]$".to_string()))
+        .match_header("authorization", auth)
+        .with_status(204)
+        .expect(2)
+        .create_async()
+        .await;
+    let reload = server
+        .mock("POST", "/rest/ip/dns/adlist/reload")
+        .match_header("authorization", auth)
+        .with_body("[]")
+        .expect(2)
+        .create_async()
+        .await;
+    let router = Router {
+        url: server.url(),
+        user: "api".to_string(),
+        password: "secret".to_string(),
+        action: DeployAction::Upload,
+        files_url: Some("http://192.168.88.10:8080/lists".to_string()),
+        accept_invalid_certs: false,
+    };
+    let unreachable = Router {
+        url: "http://127.0.0.1:1".to_string(),
+        action: DeployAction::Reload,
+        ..router.clone()
+    };
+    let reload_only = Router {
+        action: DeployAction::Reload,
+        ..router.clone()
+    };
+    let converter = Converter::new()
+        .source(format!("{}/rules", server.url()))
+        .options(RunOptions {
+            routers: vec![router, unreachable, reload_only],
+            ..Default::default()
+        })
+        .logger(|_, _| {});
+
+    let mut sink = MemorySink::default();
+    // A failed deployment is reported, but the build itself succeeds
+    let report = converter.run(&mut sink).await.unwrap();
+    assert_eq!(report.routers.len(), 3);
+    assert_eq!(report.routers[0].error, None);
+    assert!(report.routers[1].error.is_some());
+    assert_eq!(report.routers[2].error, None);
+    upload.assert_async().await;
+    stale_parts.assert_async().await;
+    reload.assert_async().await;
+}
+
+// start_paused = true: the failing source is retried with backoff
+#[tokio::test(start_paused = true)]
+async fn test_source_settings() {
+    let mut server = mockito::Server::new_async().await;
+    // Looks like a domain list, but forced to hosts format only the second
+    // line converts
+    let _list = server
+        .mock("GET", "/list")
+        .with_status(200)
+        .with_body("plain.example.com\n0.0.0.0 hosts.example.com\n")
+        .create_async()
+        .await;
+    let _missing = server
+        .mock("GET", "/missing")
+        .with_status(404)
+        .create_async()
+        .await;
+    let list = Source {
+        url: format!("{}/list", server.url()),
+        name: Some("My list".to_string()),
+        format: Some(SourceFormat::Hosts),
+        category: Some("ads".to_string()),
+        required: true,
+    };
+    let missing = Source::new(format!("{}/missing", server.url()));
+
+    let converter = Converter::new().sources([list.clone(), missing.clone()]);
+    let mut sink = MemorySink::default();
+    let report = converter.run(&mut sink).await.unwrap();
+    assert_eq!(report.total_unique, 1);
+    assert_eq!(report.sources[0].name.as_deref(), Some("My list"));
+    assert_eq!(report.sources[0].category.as_deref(), Some("ads"));
+    let hosts = String::from_utf8(sink.files["hosts.txt"].clone()).unwrap();
+    assert!(hosts.contains("# - My list --> 1 unique domains\n"));
+    assert!(hosts.contains("0.0.0.0 hosts.example.com\n"));
+
+    // A required source that fails stops the build before anything is written
+    let converter = Converter::new().sources([
+        list,
+        Source {
+            required: true,
+            ..missing
+        },
+    ]);
+    let mut sink = MemorySink::default();
+    let error = converter.run(&mut sink).await.unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "required source missing could not be fetched"
+    );
+    assert!(sink.files.is_empty());
+}
+
+#[tokio::test]
+async fn test_lookup_reports_matching_lines_and_overrides() {
+    let mut server = mockito::Server::new_async().await;
+    let _a = server
+        .mock("GET", "/a")
+        .with_status(200)
+        .with_body("! Title: list a\n||example.com^\n||other.com^\n@@||cdn.example.com^\n")
+        .create_async()
+        .await;
+    let _b = server
+        .mock("GET", "/b")
+        .with_status(200)
+        .with_body("# hosts\n0.0.0.0 ads.example.com\n0.0.0.0 x.ads.example.com\n")
+        .create_async()
+        .await;
+    let a = format!("{}/a", server.url());
+    let b = format!("{}/b", server.url());
+
+    let report = lookup(vec![&a, &b], "ads.example.com.", &RunOptions::default())
+        .await
+        .unwrap();
+    assert!(report.is_blocked());
+    assert_eq!(report.domain, "ads.example.com");
+    let found: Vec<(&str, Option<usize>, &str)> = report
+        .matches
+        .iter()
+        .map(|m| (m.source.as_str(), m.line_number, m.line.as_str()))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            (a.as_str(), Some(2), "||example.com^"),
+            (b.as_str(), Some(2), "0.0.0.0 ads.example.com"),
+        ]
+    );
+    assert!(report.to_string().contains("(parent domain example.com)"));
+
+    // The exception is reported, but it can't lift the block on the parent
+    // domain, which RouterOS applies to every subdomain
+    let report = lookup(vec![&a, &b], "cdn.example.com", &RunOptions::default())
+        .await
+        .unwrap();
+    assert!(report.is_blocked());
+    assert!(
+        report
+            .matches
+            .iter()
+            .any(|m| m.kind == MatchKind::Exception && m.line_number == Some(4))
+    );
+
+    let options = RunOptions {
+        allowlist: vec!["example.com".to_string()],
+        ..Default::default()
+    };
+    let report = lookup(vec![&a, &b], "ads.example.com", &options)
+        .await
+        .unwrap();
+    assert!(!report.is_blocked());
+    assert!(report.matches.iter().all(|m| m.kind
+        == MatchKind::Block {
+            overridden_by: Some(Override::Allowlist("example.com".to_string()))
+        }));
+
+    assert!(
+        lookup(vec![&a], "not a domain", &RunOptions::default())
+            .await
+            .is_err()
+    );
+}
+
+// start_paused = true: the failing source is retried with backoff
+#[tokio::test(start_paused = true)]
+async fn test_converter_lookup_logs_through_logger() {
+    let mut server = mockito::Server::new_async().await;
+    let _list = server
+        .mock("GET", "/list")
+        .with_body("||example.com^\n")
+        .create_async()
+        .await;
+    let _missing = server
+        .mock("GET", "/missing")
+        .with_status(404)
+        .create_async()
+        .await;
+    let missing = format!("{}/missing", server.url());
+    let messages = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let log = messages.clone();
+    let converter = Converter::new()
+        .sources([format!("{}/list", server.url()), missing.clone()])
+        .client(reqwest::Client::new())
+        .logger(move |level, message| log.lock().unwrap().push((level, message.to_string())));
+
+    let report = converter.lookup("ads.example.com").await.unwrap();
+    assert!(report.is_blocked());
+    assert_eq!(report.failed_sources, vec![missing.clone()]);
+    assert!(messages.lock().unwrap().iter().any(|(level, message)| {
+        *level == LogLevel::Warning
+            && message.starts_with(&format!("Failed to fetch rules from {missing}"))
+    }));
+}
+
+#[tokio::test]
+async fn test_lookup_ignores_case_of_list_lines() {
+    let mut server = mockito::Server::new_async().await;
+    let _rules = server
+        .mock("GET", "/rules")
+        .with_body("||Ads.Example.COM^\n||Cdn.Example.org^\n@@||cdn.example.ORG^\n")
+        .create_async()
+        .await;
+    let _hosts = server
+        .mock("GET", "/hosts")
+        .with_body("0.0.0.0 Tracker.Example.com\n")
+        .create_async()
+        .await;
+    let rules = format!("{}/rules", server.url());
+    let hosts = format!("{}/hosts", server.url());
+
+    let report = lookup(
+        vec![&rules, &hosts],
+        "ads.example.com",
+        &RunOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert!(report.is_blocked());
+    assert_eq!(report.matches[0].domain, "ads.example.com");
+    assert_eq!(report.matches[0].line, "||Ads.Example.COM^");
+    assert!(!report.to_string().contains("parent domain"));
+
+    let report = lookup(
+        vec![&rules, &hosts],
+        "CDN.example.org",
+        &RunOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(report.matches.len(), 2);
+    assert!(!report.is_blocked(), "the exception overrides the block");
+
+    let report = lookup(
+        vec![&rules, &hosts],
+        "tracker.example.com",
+        &RunOptions::default(),
+    )
+    .await
+    .unwrap();
+    assert!(report.is_blocked());
+    assert_eq!(report.matches[0].source, hosts);
+}
+
 #[tokio::test]
 async fn test_run_write_failure_leaves_no_temp_file() {
     // Regression coverage for the atomic-write error path: if OUTPUT_DIR
@@ -263,3 +1204,276 @@ async fn test_fetch_rules_filters_comments_and_empty_lines() {
     assert!(rules.contains(&"||example.com^".to_string()));
     assert!(rules.contains(&"||test.com^".to_string()));
 }
+
+#[tokio::test]
+async fn test_local_file_sources_merge_with_remote() {
+    let mut server = mockito::Server::new_async().await;
+    let _m = server
+        .mock("GET", "/rules")
+        .with_status(200)
+        .with_body("||remote.com^\n")
+        .create_async()
+        .await;
+    let dir = tempdir().unwrap();
+    let hosts = dir.path().join("in house.txt");
+    std::fs::write(
+        &hosts,
+        "\u{feff}# in-house list\n0.0.0.0 intranet-ads.example\n0.0.0.0 remote.com\n",
+    )
+    .unwrap();
+    let domains = dir.path().join("domains.txt");
+    std::fs::write(&domains, "tracker.example\n").unwrap();
+    let file_url = reqwest::Url::from_file_path(&domains).unwrap().to_string();
+
+    let client = reqwest::Client::new();
+    let rules = fetch_rules(&client, hosts.to_str().unwrap()).await.unwrap();
+    assert_eq!(
+        rules,
+        ["0.0.0.0 intranet-ads.example", "0.0.0.0 remote.com"],
+        "BOM stripped and comments filtered, as for remote lists"
+    );
+
+    let converter = Converter::new().sources([
+        format!("{}/rules", server.url()),
+        hosts.to_string_lossy().into_owned(),
+        file_url,
+    ]);
+    let mut sink = MemorySink::default();
+    let report = converter.run(&mut sink).await.unwrap();
+    assert_eq!(report.total_unique, 3);
+    assert_eq!(report.sources[1].format, Some(SourceFormat::Hosts));
+    assert_eq!(report.sources[1].duplicates, 1);
+    assert_eq!(report.sources[2].format, Some(SourceFormat::Domains));
+
+    let missing = dir.path().join("missing.txt");
+    let error = fetch_rules(&client, missing.to_str().unwrap())
+        .await
+        .unwrap_err();
+    assert!(format!("{error:#}").contains("Failed to read"));
+}
+
+#[tokio::test]
+async fn test_run_writes_one_file_per_category() {
+    let mut server = mockito::Server::new_async().await;
+    let _ads = server
+        .mock("GET", "/ads")
+        .with_status(200)
+        .with_body("||shared.com^\n||ads.com^\n||x.gamble.com^\n")
+        .create_async()
+        .await;
+    let _gambling = server
+        .mock("GET", "/gambling")
+        .with_status(200)
+        .with_body("||shared.com^\n||gamble.com^\n")
+        .create_async()
+        .await;
+    let _misc = server
+        .mock("GET", "/misc")
+        .with_status(200)
+        .with_body("||misc.com^\n||shared.com^\n")
+        .create_async()
+        .await;
+    let source = |path: &str, category: Option<&str>| Source {
+        category: category.map(str::to_string),
+        ..Source::new(format!("{}/{path}", server.url()))
+    };
+    let sources = [
+        source("ads", Some("ads")),
+        source("gambling", Some("gambling")),
+        source("misc", None),
+    ];
+    let options = RunOptions {
+        categories: Some(CategoryOptions {
+            priority: vec!["gambling".to_string()],
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    let converter = Converter::new()
+        .sources(sources.clone())
+        .options(options.clone());
+    let mut sink = MemorySink::default();
+    let report = converter.run(&mut sink).await.unwrap();
+    let file = |name: &str| String::from_utf8(sink.files[name].clone()).unwrap();
+
+    // gambling has priority, so it keeps shared.com
+    let gambling = file("hosts-gambling.txt");
+    assert!(gambling.contains("# Category: gambling\n"));
+    assert!(gambling.contains("0.0.0.0 shared.com\n0.0.0.0 gamble.com\n"));
+    assert!(gambling.ends_with("# Total unique domains: 2\n"));
+    assert!(
+        gambling.contains("/hosts-gambling.txt\n"),
+        "URL points at the file"
+    );
+
+    // x.gamble.com is not pruned: an ads-only router still needs it
+    let ads = file("hosts-ads.txt");
+    assert!(ads.contains("0.0.0.0 ads.com\n0.0.0.0 x.gamble.com\n"));
+    assert!(!ads.contains("shared.com"));
+    assert!(ads.ends_with("# Total unique domains: 2\n"));
+
+    // Uncategorized sources only go to the combined file, which is pruned
+    // across categories
+    let hosts = file("hosts.txt");
+    assert!(hosts.contains("0.0.0.0 misc.com\n"));
+    assert_eq!(hosts.matches("0.0.0.0 shared.com\n").count(), 1);
+    assert!(!hosts.contains("x.gamble.com"));
+    assert!(hosts.ends_with("# Total unique domains: 4\n"));
+    assert_eq!(report.total_unique, 4);
+
+    // Reports stay in source order, with duplicates counted per owner
+    assert!(report.sources[0].url.ends_with("/ads"));
+    assert_eq!(report.sources[0].duplicates, 1);
+    assert_eq!(report.sources[1].duplicates, 0);
+    assert_eq!(report.sources[2].duplicates, 1);
+
+    let converter = Converter::new().sources(sources).options(RunOptions {
+        categories: Some(CategoryOptions {
+            combined: false,
+            ..Default::default()
+        }),
+        ..options
+    });
+    let mut sink = MemorySink::default();
+    converter.run(&mut sink).await.unwrap();
+    let mut names: Vec<&String> = sink.files.keys().collect();
+    names.sort();
+    assert_eq!(names, ["hosts-ads.txt", "hosts-gambling.txt"]);
+    // Without a priority, the first category listing a domain owns it
+    let ads = String::from_utf8(sink.files["hosts-ads.txt"].clone()).unwrap();
+    assert!(ads.contains("0.0.0.0 shared.com\n"));
+}
+
+#[tokio::test]
+async fn test_run_writes_compressed_copies() {
+    use std::io::Read;
+
+    let mut server = mockito::Server::new_async().await;
+    let _m = server
+        .mock("GET", "/rules")
+        .with_status(200)
+        .with_body("||example.com^\n||test.com^\n")
+        .create_async()
+        .await;
+    let converter = Converter::new()
+        .source(format!("{}/rules", server.url()))
+        .options(RunOptions {
+            compression: vec![Compression::Gzip, Compression::Zstd],
+            max_entries_per_file: Some(1),
+            ..Default::default()
+        });
+    let mut sink = MemorySink::default();
+    // A part left over from a larger build, with its compressed copy
+    for name in ["hosts-3.txt", "hosts-3.txt.gz"] {
+        sink.files.insert(name.to_string(), b"stale".to_vec());
+    }
+    converter.run(&mut sink).await.unwrap();
+
+    let plain = sink.files["hosts-1.txt"].clone();
+    let mut gunzipped = Vec::new();
+    flate2::read::GzDecoder::new(&sink.files["hosts-1.txt.gz"][..])
+        .read_to_end(&mut gunzipped)
+        .unwrap();
+    assert_eq!(gunzipped, plain);
+    let unzstd = zstd::decode_all(&sink.files["hosts-1.txt.zst"][..]).unwrap();
+    assert_eq!(unzstd, plain);
+    assert!(sink.files.contains_key("hosts-index.txt.gz"));
+    assert!(sink.files.contains_key("hosts-2.txt.zst"));
+    assert!(!sink.files.contains_key("hosts-3.txt"));
+    assert!(!sink.files.contains_key("hosts-3.txt.gz"));
+
+    // Dropping a compression from the config removes its copies
+    let converter = converter.options(RunOptions {
+        compression: vec![Compression::Gzip],
+        max_entries_per_file: Some(1),
+        ..Default::default()
+    });
+    converter.run(&mut sink).await.unwrap();
+    assert!(sink.files.contains_key("hosts-1.txt.gz"));
+    assert!(!sink.files.keys().any(|name| name.ends_with(".zst")));
+}
+
+fn gzip(body: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[tokio::test]
+async fn test_fetch_rules_decompresses_compressed_sources() {
+    let mut server = mockito::Server::new_async().await;
+    let list = b"||example.com^\n||test.com^\n";
+    // Declared with Content-Encoding
+    let _encoded = server
+        .mock("GET", "/encoded")
+        .with_status(200)
+        .with_header("content-encoding", "gzip")
+        .with_body(gzip(list))
+        .create_async()
+        .await;
+    // A .gz file, declared by its content type only
+    let _typed = server
+        .mock("GET", "/list.txt.gz")
+        .with_status(200)
+        .with_header("content-type", "application/x-gzip")
+        .with_body(gzip(list))
+        .create_async()
+        .await;
+    // Recognized by its magic bytes
+    let _sniffed = server
+        .mock("GET", "/list.txt.zst")
+        .with_status(200)
+        .with_header("content-type", "application/octet-stream")
+        .with_body(zstd::encode_all(&list[..], 3).unwrap())
+        .create_async()
+        .await;
+    // A .gz file compressed again for transfer
+    let _twice = server
+        .mock("GET", "/twice.gz")
+        .with_status(200)
+        .with_header("content-encoding", "gzip")
+        .with_body(gzip(&gzip(list)))
+        .create_async()
+        .await;
+    // Labelled gzip, but plain text: used as it is
+    let _mislabelled = server
+        .mock("GET", "/mislabelled")
+        .with_status(200)
+        .with_header("content-encoding", "gzip")
+        .with_body("||example.com^\n||test.com^\n")
+        .create_async()
+        .await;
+    let _corrupt = server
+        .mock("GET", "/corrupt")
+        .with_status(200)
+        .with_body(&gzip(list)[..12])
+        .create_async()
+        .await;
+
+    let client = reqwest::Client::new();
+    for path in [
+        "encoded",
+        "list.txt.gz",
+        "list.txt.zst",
+        "twice.gz",
+        "mislabelled",
+    ] {
+        let rules = fetch_rules(&client, &format!("{}/{path}", server.url()))
+            .await
+            .unwrap();
+        assert_eq!(rules, ["||example.com^", "||test.com^"], "{path}");
+    }
+
+    let error = fetch_rules(&client, &format!("{}/corrupt", server.url()))
+        .await
+        .unwrap_err();
+    assert!(format!("{error:#}").contains("Failed to decompress"));
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("local.txt.gz");
+    std::fs::write(&path, gzip(b"0.0.0.0 local.example\n")).unwrap();
+    let rules = fetch_rules(&client, path.to_str().unwrap()).await.unwrap();
+    assert_eq!(rules, ["0.0.0.0 local.example"]);
+}